@@ -0,0 +1,85 @@
+// Standard base64 (RFC 4648), with padding.
+
+const ALPHABET: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn sextet(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Appends the encoding of input to out.
+pub fn encode(input: &[u8], out: &mut String) {
+    for chunk in input.chunks(3) {
+        let n = chunk.iter().enumerate().fold(
+            0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(if i <= chunk.len() {
+                ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char
+            } else {
+                '='
+            });
+        }
+    }
+}
+
+/// Appends the bytes encoded by input to out.
+pub fn decode(input: &[u8], out: &mut Vec<u8>) -> Result<(), &'static str> {
+    if input.len() % 4 != 0 {
+        return Err("base64 length not a multiple of 4");
+    }
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = (i + 1) * 4 == input.len();
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("misplaced base64 padding");
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            n = n << 6 | try!(sextet(c).ok_or("invalid base64 character"));
+        }
+        n <<= 6 * padding as u32;
+        out.extend(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - padding]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(s: &str) -> Result<Vec<u8>, &'static str> {
+        let mut out = vec!();
+        decode(s.as_bytes(), &mut out).map(|_| out)
+    }
+
+    #[test]
+    fn test_encode() {
+        for &(raw, encoded) in &[(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="),
+                                 (b"foobar", "Zm9vYmFy"), (b"\xfb\xff", "+/8=")] {
+            let mut out = String::new();
+            encode(raw, &mut out);
+            assert_eq!(out, encoded);
+            assert_eq!(decoded(encoded), Ok(raw.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decoded(""), Ok(vec!()));
+        assert_eq!(decoded("Zg=="), Ok(b"f".to_vec()));
+        assert_eq!(decoded("Zm8="), Ok(b"fo".to_vec()));
+        assert_eq!(decoded("Zm9vYmFy"), Ok(b"foobar".to_vec()));
+        assert_eq!(decoded("+/8="), Ok(vec![0xfb, 0xff]));
+        assert!(decoded("Zm9").is_err());
+        assert!(decoded("Zg==Zg==").is_err());
+        assert!(decoded("Z===").is_err());
+        assert!(decoded("Zm9!").is_err());
+    }
+}
@@ -1,5 +1,177 @@
 pub mod parser;
 pub mod typecheck;
 
+use std::fmt;
+use std::error::Error;
+use std::ops::Range;
+use ::descriptors::Type;
 
+use self::parser::ParseError;
+use self::typecheck::TypeError;
 
+/// An error from compiling a query, with the byte range of the query
+/// string it refers to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub message: &'static str,
+    pub query: String,
+    pub span: Range<usize>,
+    /// The field being checked when the error was found.
+    pub field: Option<String>,
+    /// The fully qualified message type the field was looked up in.
+    pub message_type: Option<String>,
+    /// What the query needed here, e.g. "a number".
+    pub expected: Option<&'static str>,
+    pub actual: Option<Type>,
+    /// For an unknown enum value name, the names that would have worked.
+    pub valid_values: Vec<String>,
+}
+
+/// Something in a query that compiles but can never match, such as a
+/// comparison between two members of the same oneof.
+pub type CompileWarning = CompileError;
+
+// Spans are slices of the query, so their offset is a pointer difference.
+fn span_of(query: &str, at: &str) -> Range<usize> {
+    let start = at.as_ptr() as usize;
+    let base = query.as_ptr() as usize;
+    if start < base || start + at.len() > base + query.len() {
+        return 0..query.len();
+    }
+    start - base..start - base + at.len()
+}
+
+// A parse error is at the rest of the input; point at just its first
+// token: a single delimiter, or a run up to the next delimiter or space.
+fn first_token(rest: &str) -> &str {
+    let delim = |c: char| c.is_whitespace() || "[](){}.,:=!~<>".contains(c);
+    let end = match rest.chars().next() {
+        Some(c) if delim(c) => c.len_utf8(),
+        Some(_) => rest.find(delim).unwrap_or(rest.len()),
+        None => 0,
+    };
+    &rest[..end]
+}
+
+impl CompileError {
+    pub fn from_parse(query: &str, e: ParseError) -> CompileError {
+        CompileError { message: e.message,
+                       query: query.to_string(),
+                       span: span_of(query, first_token(e.at)),
+                       field: None, message_type: None,
+                       expected: None, actual: None, valid_values: vec!() }
+    }
+
+    pub fn from_typecheck(query: &str, e: TypeError) -> CompileError {
+        CompileError { message: e.message,
+                       query: query.to_string(),
+                       span: e.at.map_or(0..query.len(),
+                                         |at| span_of(query, at)),
+                       field: e.field.map(|f| f.to_string()),
+                       message_type: e.message_type,
+                       expected: e.expected,
+                       actual: e.actual,
+                       valid_values: e.valid_values }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.message));
+        if let Some(expected) = self.expected {
+            try!(write!(f, ": expected {}", expected));
+            if let Some(actual) = self.actual {
+                try!(write!(f, ", found {:?}", actual));
+            }
+        }
+        if !self.valid_values.is_empty() {
+            try!(write!(f, ": expected one of {}", self.valid_values.join(", ")));
+        }
+        match (&self.field, &self.message_type) {
+            (&Some(ref field), &Some(ref mt)) =>
+                try!(write!(f, " (field `{}` in `{}`)", field, mt)),
+            (&Some(ref field), &None) =>
+                try!(write!(f, " (field `{}`)", field)),
+            _ => (),
+        }
+        // Columns count chars so the caret lines up under non-ASCII text.
+        let pad = self.query[..self.span.start].chars().count();
+        let width = self.query[self.span.clone()].chars().count();
+        write!(f, "\n  {}\n  {}{}", self.query,
+               " ".repeat(pad), "^".repeat(if width == 0 { 1 } else { width }))
+    }
+}
+
+impl Error for CompileError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::compile;
+    use ::descriptors::native::tests::transit_pool;
+
+    #[test]
+    fn test_display() {
+        let query = "stops[name > 3]";
+        let e = CompileError { message: "type mismatch",
+                               query: query.to_string(),
+                               span: 6..10,
+                               field: Some("name".to_string()),
+                               message_type: Some("transit.Stop".to_string()),
+                               expected: Some("a number"),
+                               actual: Some(Type::STRING),
+                               valid_values: vec!() };
+        assert_eq!(e.to_string(),
+                   "type mismatch: expected a number, found STRING \
+                    (field `name` in `transit.Stop`)\n  \
+                    stops[name > 3]\n        ^^^^");
+    }
+
+    #[test]
+    fn test_parse_span() {
+        let query = "stops.name!";
+        let e = parser::parse(query).unwrap_err();
+        let e = CompileError::from_parse(query, e);
+        assert_eq!(e.span, 10..11);
+        assert!(e.to_string().ends_with("\n  stops.name!\n            ^"));
+    }
+
+    #[test]
+    fn test_parse_span_mid_query() {
+        let query = "stops[name = 'x' @@ id > 2].name";
+        let e = parser::parse(query).unwrap_err();
+        let e = CompileError::from_parse(query, e);
+        assert_eq!(e.span, 17..19);
+        assert!(e.span.end != query.len());
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+
+        let e = compile("stops[name > 3]", feed).unwrap_err();
+        assert_eq!(e.message, "type mismatch");
+        assert_eq!(e.span, 6..10);
+        assert_eq!(e.field, Some("name".to_string()));
+        assert_eq!(e.expected, Some("a number"));
+        assert_eq!(e.actual, Some(Type::STRING));
+
+        let e = compile("stops.nmae", feed).unwrap_err();
+        assert_eq!(e.message, "No such field");
+        assert_eq!(e.span, 6..10);
+        assert_eq!(e.message_type, Some("transit.Stop".to_string()));
+
+        let e = compile("timestamp.seconds", feed).unwrap_err();
+        assert_eq!(e.message, "Not a message");
+        assert_eq!(e.span, 10..17);
+
+        let e = compile("stops[1 > 2]", feed).unwrap_err();
+        assert_eq!(e.message, "Constant folding produced false");
+        assert_eq!(e.span, 6..11);
+
+        let e = compile("stops.#0", feed).unwrap_err();
+        assert_eq!(e.message, "Invalid field number");
+        assert_eq!(e.span, 6..8);
+    }
+}
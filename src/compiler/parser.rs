@@ -1,5 +1,16 @@
-pub type ParseError = &'static str;
-pub type ParseResult<'a, T> = Result<(T, &'a str), ParseError>;
+use ::query::CmpOp;
+
+// at is the remaining input where the error was found.
+#[derive(Debug)]
+pub struct ParseError<'a> {
+    pub message: &'static str,
+    pub at: &'a str,
+}
+pub type ParseResult<'a, T> = Result<(T, &'a str), ParseError<'a>>;
+
+fn fail<'a, T>(message: &'static str, at: &'a str) -> ParseResult<'a, T> {
+    Err(ParseError { message: message, at: at })
+}
 type Parser<'i, T> = Box<Fn(&'i str) -> ParseResult<'i, T> + 'i>;
 
 fn is_id_start(ch: char) -> bool {
@@ -10,10 +21,20 @@ fn is_id_continue(ch: char) -> bool {
     ch.is_alphanumeric() || ch == '_'
 }
 
+// The keyword kw, not followed by further identifier characters.
+fn keyword<'a>(input: &'a str, kw: &str) -> Option<&'a str> {
+    if !input.starts_with(kw) { return None }
+    let tail = &input[kw.len()..];
+    match tail.chars().nth(0) {
+        Some(c) if is_id_continue(c) => None,
+        _ => Some(tail),
+    }
+}
+
 fn ident<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
     let head = input.chars().next();
     if head.is_none() || !is_id_start(head.unwrap()) {
-        return Err("Expected identifier");
+        return fail("Expected identifier", input);
     }
     for (i, c) in input.char_indices() {
         if !is_id_continue(c) {
@@ -23,36 +44,68 @@ fn ident<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
     return Ok((input, ""));
 }
 
-#[derive(PartialEq)]
-enum Num { Inty(i32), Floaty(f64) }
+// Unsigned only for integers too big for an i64, such as large uint64 ids.
+#[derive(PartialEq, Debug)]
+pub enum Num { Inty(i64), Unsigned(u64), Floaty(f64) }
 
-extern {
-    fn strtod(s: *const u8, endptr: *mut *mut u8) -> f64;
-    fn strtol(s: *const u8, endptr: *mut *mut u8) -> isize;
+impl Num {
+    pub fn into_item<'a>(self) -> RawItem<'a> {
+        match self {
+            Num::Inty(i) => RawItem::IntItem(i),
+            Num::Unsigned(u) => RawItem::UintItem(u),
+            Num::Floaty(f) => RawItem::FloatItem(f),
+        }
+    }
 }
+
+fn count_digits(input: &[u8]) -> usize {
+    input.iter().take_while(|b| b.is_ascii_digit()).count()
+}
+
+// [+-]digits[.digits][e[+-]digits], where a fraction or exponent
+// makes it a float.
 fn parsenum<'a>(input: &'a str) -> ParseResult<'a, Num> {
-    let mut endf: *mut u8 = ::std::ptr::null_mut();
-    let mut endi: *mut u8 = ::std::ptr::null_mut();
-
-    let in_cstr = input.as_ptr(); // TODO: not very safe.
-    let fval = unsafe { strtod(in_cstr, &mut endf as *mut *mut u8) };
-    let ival = unsafe { strtol(in_cstr, &mut endi as *mut *mut u8) as i32 };
-    if endi as usize == 0 { return Err("Not a number"); }
-
-    let intbytes = endi as usize - in_cstr as usize;
-    let floatbytes = endf as usize - in_cstr as usize;
-    if floatbytes == 0 {
-        Err("Not a number")
-    } else if intbytes == floatbytes {
-        Ok((Num::Inty(ival), &input[intbytes..]))
+    let bytes = input.as_bytes();
+    let mut end = 0;
+    if let Some(&b'-') | Some(&b'+') = bytes.get(0) { end += 1 }
+    let intdigits = count_digits(&bytes[end..]);
+    end += intdigits;
+    let mut floaty = false;
+    let mut fracdigits = 0;
+    if let Some(&b'.') = bytes.get(end) {
+        floaty = true;
+        fracdigits = count_digits(&bytes[end + 1..]);
+        end += 1 + fracdigits;
+    }
+    if intdigits == 0 && fracdigits == 0 { return fail("Not a number", input); }
+    if let Some(&b'e') | Some(&b'E') = bytes.get(end) {
+        let mut exp = end + 1;
+        if let Some(&b'-') | Some(&b'+') = bytes.get(exp) { exp += 1 }
+        let expdigits = count_digits(&bytes[exp..]);
+        if expdigits > 0 {
+            floaty = true;
+            end = exp + expdigits;
+        }
+    }
+
+    let (num, tail) = input.split_at(end);
+    if floaty {
+        match num.parse() {
+            Ok(f) => Ok((Num::Floaty(f), tail)),
+            Err(_) => fail("Not a number", input),
+        }
     } else {
-        Ok((Num::Floaty(fval), &input[floatbytes..]))
+        match (num.parse(), num.parse()) {
+            (Ok(i), _) => Ok((Num::Inty(i), tail)),
+            (_, Ok(u)) => Ok((Num::Unsigned(u), tail)),
+            _ => fail("Integer literal out of range", input),
+        }
     }
 }
 
 fn quoted_string<'a>(input: &'a str) -> ParseResult<'a, String> {
-    let delim = input.chars().nth(0).unwrap();
-    if delim != '\'' && delim != '"' { return Err("No quotes?"); }
+    let delim = input.chars().nth(0).unwrap_or('\0');
+    if delim != '\'' && delim != '"' { return fail("No quotes?", input); }
     let mut result = String::new();
     let mut escape = false;
     for (i, c) in input[1..].char_indices() {
@@ -64,7 +117,7 @@ fn quoted_string<'a>(input: &'a str) -> ParseResult<'a, String> {
         }
         result.push(c);
     }
-    Err("No trailing delimiter?")
+    fail("No trailing delimiter?", input)
 }
 
 /////////////////////////////////
@@ -72,16 +125,22 @@ fn quoted_string<'a>(input: &'a str) -> ParseResult<'a, String> {
 pub enum RawItem<'a> {
     Path(Box<Path<'a>>),
     AtItem,
-    IntItem(i32),
+    IntItem(i64),
+    // an integer literal above i64::MAX
+    UintItem(u64),
     FloatItem(f64),
     StrItem(String),
-    ListItem(Vec<RawItem<'a>>)
+    BoolItem(bool),
+    ListItem(Vec<RawItem<'a>>),
+    // which(name), the member of oneof name that is set
+    Which(&'a str),
 }
 impl<'a> RawItem<'a> {
     pub fn is_atom(&self) -> bool {
         match self {
             &RawItem::FloatItem(_) | &RawItem::IntItem(_)
-                | &RawItem::StrItem(_) => true,
+                | &RawItem::UintItem(_) | &RawItem::StrItem(_)
+                | &RawItem::BoolItem(_) => true,
             _ => false,
         }
     }
@@ -93,10 +152,16 @@ impl<'a> RawItem<'a> {
         }
     }
     pub fn is_int(&self) -> bool {
-        if let &RawItem::IntItem(_) = self { true } else { false }
+        match self {
+            &RawItem::IntItem(_) | &RawItem::UintItem(_) => true,
+            _ => false,
+        }
     }
     pub fn is_str(&self) -> bool {
-        if let &RawItem::IntItem(_) = self { true } else { false }
+        if let &RawItem::StrItem(_) = self { true } else { false }
+    }
+    pub fn is_bool(&self) -> bool {
+        if let &RawItem::BoolItem(_) = self { true } else { false }
     }
 }
 
@@ -118,8 +183,27 @@ fn parse_list(input: &str) -> ParseResult<Vec<RawItem>> {
     Ok((result, tail))
 }
 
+fn parse_which<'a>(input: &'a str) -> ParseResult<'a, RawItem<'a>> {
+    let tail = match keyword(input, "which") {
+        Some(t) => t.trim_left(),
+        None => return fail("Expected which", input),
+    };
+    if let Some('(') = tail.chars().nth(0) {} else {
+        return fail("Expected (", tail)
+    }
+    let (name, tail) = try!(ident(tail[1..].trim_left()));
+    let tail = tail.trim_left();
+    if let Some(')') = tail.chars().nth(0) {} else {
+        return fail("Expected )", tail)
+    }
+    Ok((RawItem::Which(name), &tail[1..]))
+}
+
 fn parse_item(input: &str) -> ParseResult<RawItem> {
-    let first = try!(input.chars().nth(0).ok_or("End of input"));
+    let first = match input.chars().nth(0) {
+        Some(c) => c,
+        None => return fail("End of input", input),
+    };
     if first == '@' {
         Ok((RawItem::AtItem, &input[1..]))
     } else if first == '\'' || first == '"' {
@@ -127,17 +211,20 @@ fn parse_item(input: &str) -> ParseResult<RawItem> {
         Ok((RawItem::StrItem(s), tail))
     } else if first.is_numeric() || first == '-' || first == '+' {
         let (n, tail) = try!(parsenum(input));
-        match n {
-            Num::Inty(i) => Ok((RawItem::IntItem(i), tail)),
-            Num::Floaty(f) => Ok((RawItem::FloatItem(f), tail)),
-        }
+        Ok((n.into_item(), tail))
     } else if first == '(' {
         let (l, tail) = try!(parse_list(&input[1..]));
         let tail = tail.trim_left();
         match tail.chars().nth(0) {
-            Some('(') => Ok((RawItem::ListItem(l), &tail[1..])),
-            _ => Err("Could not parse list")
+            Some(')') => Ok((RawItem::ListItem(l), &tail[1..])),
+            _ => fail("Could not parse list", tail)
         }
+    } else if let Ok(r) = parse_which(input) {
+        Ok(r)
+    } else if let Some(tail) = keyword(input, "true") {
+        Ok((RawItem::BoolItem(true), tail))
+    } else if let Some(tail) = keyword(input, "false") {
+        Ok((RawItem::BoolItem(false), tail))
     } else {
         let (p, tail) = try!(parse_path(input));
         Ok((RawItem::Path(Box::new(p)), tail))
@@ -145,11 +232,13 @@ fn parse_item(input: &str) -> ParseResult<RawItem> {
 }
 
 
-enum Op { Eq, NotEq, Rx, NotRx, In }
+enum Op { Eq, NotEq, Rx, NotRx, In, Cmp(CmpOp) }
 fn parse_op(input: &str) -> ParseResult<Op> {
-    let errmsg = "Expected operator, got end of input";
-    let ch1 = try!(input.chars().nth(0).ok_or(errmsg));
-    let ch2 = try!(input.chars().nth(1).ok_or(errmsg)); 
+    let mut chars = input.chars();
+    let (ch1, ch2) = match (chars.next(), chars.next()) {
+        (Some(ch1), Some(ch2)) => (ch1, ch2),
+        _ => return fail("Expected operator, got end of input", input),
+    };
     match (ch1, ch2) {
         ('=', '=') => Ok((Op::Eq, &input[2..])),
         ('=', _) => Ok((Op::Eq, &input[1..])),
@@ -157,7 +246,11 @@ fn parse_op(input: &str) -> ParseResult<Op> {
         ('!', '=') => Ok((Op::NotEq, &input[2..])),
         ('!', '~') => Ok((Op::NotRx, &input[2..])),
         ('i', 'n') => Ok((Op::In, &input[2..])),
-        _ => Err("Invalid operator")
+        ('<', '=') => Ok((Op::Cmp(CmpOp::Le), &input[2..])),
+        ('<', _) => Ok((Op::Cmp(CmpOp::Lt), &input[1..])),
+        ('>', '=') => Ok((Op::Cmp(CmpOp::Ge), &input[2..])),
+        ('>', _) => Ok((Op::Cmp(CmpOp::Gt), &input[1..])),
+        _ => fail("Invalid operator", input)
     }
 }
 
@@ -167,15 +260,57 @@ pub enum RawFilter<'a> {
     EqFilter(RawItem<'a>, RawItem<'a>, bool),
     RxFilter(RawItem<'a>, RawItem<'a>, bool),
     InFilter(RawItem<'a>, Vec<RawItem<'a>>),
-    IdxFilter(i32),
+    CmpFilter(RawItem<'a>, RawItem<'a>, CmpOp),
+    // a bare integer: an index, or a key if the field is a map, which
+    // only the typechecker knows
+    IdxFilter(Num),
+    SliceFilter(Option<i32>, Option<i32>),
+    // a bare string or bool, for looking up a map key
+    KeyFilter(RawItem<'a>),
+    // [*], every element
+    AllFilter,
+    HasFilter(RawItem<'a>, bool),
+    And(Box<RawFilter<'a>>, Box<RawFilter<'a>>),
+    Or(Box<RawFilter<'a>>, Box<RawFilter<'a>>),
+    Not(Box<RawFilter<'a>>),
 }
+
+fn index_value(i: i64, at: &str) -> Result<i32, ParseError> {
+    if i < i32::min_value() as i64 || i > i32::max_value() as i64 {
+        Err(ParseError { message: "Index out of range", at: at })
+    } else {
+        Ok(i as i32)
+    }
+}
+
+fn parse_index(input: &str) -> ParseResult<Option<i32>> {
+    match parsenum(input) {
+        Ok((Num::Inty(i), tail)) =>
+            Ok((Some(try!(index_value(i, input))), tail)),
+        Ok((Num::Unsigned(_), _)) => fail("Index out of range", input),
+        Ok((Num::Floaty(_), _)) => fail("Index must be an integer", input),
+        Err(_) => Ok((None, input)),
+    }
+}
+
+// start:end, where either end may be omitted
+fn parse_slice(input: &str) -> ParseResult<RawFilter> {
+    let (start, tail) = try!(parse_index(input));
+    let tail = tail.trim_left();
+    if let Some(':') = tail.chars().nth(0) {} else {
+        return fail("Expected ':'", tail)
+    }
+    let (end, tail) = try!(parse_index(tail[1..].trim_left()));
+    Ok((RawFilter::SliceFilter(start, end), tail.trim_left()))
+}
+
 fn parse_expr<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
     let tail = input.trim_left();
     let (left, tail) = try!(parse_item(tail));
     let tail = tail.trim_left();
     if let Ok((op, tail)) = parse_op(tail) {
-        let tail = tail.trim_left();
-        let (right, tail) = try!(parse_item(tail));
+        let rightsrc = tail.trim_left();
+        let (right, tail) = try!(parse_item(rightsrc));
         let result = match op {
             Op::Eq => RawFilter::EqFilter(left, right, false),
             Op::NotEq => RawFilter::EqFilter(left, right, true),
@@ -183,54 +318,160 @@ fn parse_expr<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
             Op::NotRx => RawFilter::RxFilter(left, right, true),
             Op::In => if let RawItem::ListItem(l) = right {
                 RawFilter::InFilter(left, l)
-            } else { return Err("right hand of 'in' must be a list") }
+            } else {
+                return fail("right hand of 'in' must be a list", rightsrc)
+            },
+            Op::Cmp(cmp) => RawFilter::CmpFilter(left, right, cmp),
             //_ => return Err("Filter not implemented yet")
         };
         return Ok((result, tail))
     } else {
-        if let RawItem::IntItem(i) = left {
-            Ok((RawFilter::IdxFilter(i), tail))
+        // a bare path is a boolean field that has to be true
+        let bare = if let RawItem::Path(ref p) = left { !p.is_empty() }
+                   else { false };
+        let num = match left {
+            RawItem::IntItem(i) => Some(Num::Inty(i)),
+            RawItem::UintItem(u) => Some(Num::Unsigned(u)),
+            _ => None,
+        };
+        if let Some(n) = num {
+            Ok((RawFilter::IdxFilter(n), tail))
+        } else if bare {
+            Ok((RawFilter::EqFilter(left, RawItem::BoolItem(true), false),
+                tail))
+        } else if left.is_str() || left.is_bool() {
+            Ok((RawFilter::KeyFilter(left), tail))
         } else {
-            Err("Could not parse filter")
+            fail("Could not parse filter", tail)
         }
     }
 
 }
 
+// has(path), exists(path), or either negated with a leading !
+fn parse_has<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
+    let (invert, tail) = if let Some('!') = input.chars().nth(0) {
+        (true, input[1..].trim_left())
+    } else {
+        (false, input)
+    };
+    let tail = match keyword(tail, "has").or_else(|| keyword(tail, "exists")) {
+        Some(t) => t.trim_left(),
+        None => return fail("Expected has or exists", tail),
+    };
+    if let Some('(') = tail.chars().nth(0) {} else {
+        return fail("Expected (", tail)
+    }
+    let (item, tail) = try!(parse_item(tail[1..].trim_left()));
+    let tail = tail.trim_left();
+    if let Some(')') = tail.chars().nth(0) {} else {
+        return fail("Expected )", tail)
+    }
+    Ok((RawFilter::HasFilter(item, invert), &tail[1..]))
+}
+
+// not binds tighter than and, which binds tighter than or.
+fn parse_not<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
+    let tail = input.trim_left();
+    if let Some(t) = keyword(tail, "not") {
+        let (f, t) = try!(parse_not(t));
+        return Ok((RawFilter::Not(Box::new(f)), t));
+    }
+    if let Ok(r) = parse_has(tail) {
+        return Ok(r);
+    }
+    if let Some('(') = tail.chars().nth(0) {
+        if let Ok((f, t)) = parse_or(&tail[1..]) {
+            let t = t.trim_left();
+            if let Some(')') = t.chars().nth(0) {
+                return Ok((f, &t[1..]));
+            }
+        }
+    }
+    parse_expr(tail)
+}
+
+fn parse_and<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
+    let (mut left, mut tail) = try!(parse_not(input));
+    while let Some(t) = keyword(tail.trim_left(), "and") {
+        let (right, t) = try!(parse_not(t));
+        left = RawFilter::And(Box::new(left), Box::new(right));
+        tail = t;
+    }
+    Ok((left, tail))
+}
+
+fn parse_or<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
+    let (mut left, mut tail) = try!(parse_and(input));
+    while let Some(t) = keyword(tail.trim_left(), "or") {
+        let (right, t) = try!(parse_and(t));
+        left = RawFilter::Or(Box::new(left), Box::new(right));
+        tail = t;
+    }
+    Ok((left, tail))
+}
+
+fn parse_filter<'a>(input: &'a str) -> ParseResult<'a, RawFilter> {
+    let tail = input.trim_left();
+    if let Ok(r) = parse_slice(tail) {
+        return Ok(r);
+    }
+    if let Some('*') = tail.chars().nth(0) {
+        return Ok((RawFilter::AllFilter, tail[1..].trim_left()));
+    }
+    let (f, tail) = try!(parse_or(tail));
+    Ok((f, tail.trim_left()))
+}
+
 #[derive(Debug)]
 pub struct PathPart<'a> {
     pub path: &'a str,
-    pub filter: RawFilter<'a>
+    pub filter: RawFilter<'a>,
+    // The source text of the filter, for error messages.
+    pub filter_src: &'a str,
 }
 pub type Path<'a> = Vec<PathPart<'a>>;
 
+// A field name, or #N for field number N.
+fn field_name<'a>(input: &'a str) -> ParseResult<'a, &'a str> {
+    if input.starts_with('#') {
+        let digits = count_digits(input[1..].as_bytes());
+        if digits == 0 {
+            return fail("Expected field number", input);
+        }
+        return Ok(input.split_at(digits + 1));
+    }
+    ident(input)
+}
+
 fn parse_path<'a>(input: &'a str) -> ParseResult<'a, Path<'a>> {
     let mut tail = input;
     let mut parts = Vec::new();
-    while let Ok((id, t)) = ident(tail) {
+    while let Ok((id, t)) = field_name(tail) {
         tail = t;
-        let filter = if let Some('[') = tail.chars().nth(0) {
-            let (f, t) = try!(parse_expr(&tail[1..]));
+        let (filter, src) = if let Some('[') = tail.chars().nth(0) {
+            let src = tail[1..].trim_left();
+            let (f, t) = try!(parse_filter(src));
             if let Some(']') = t.chars().nth(0) {
                 tail = &t[1..];
-                f
+                (f, src[..src.len() - t.len()].trim_right())
             } else {
-                return Err("couldn't find trailing ]");
+                return fail("couldn't find trailing ]", t);
             }
         } else {
-            RawFilter::TrueFilter
+            (RawFilter::TrueFilter, &tail[..0])
         };
-        parts.push(PathPart { path: id, filter: filter });
+        parts.push(PathPart { path: id, filter: filter, filter_src: src });
         if let Some('.') = tail.chars().nth(0) { tail = &tail[1..] } else { break }
     }
     Ok((parts, tail))
 }
 
-pub fn parse<'a>(input: &'a str) -> Result<Path<'a>, ParseError> {
+pub fn parse<'a>(input: &'a str) -> Result<Path<'a>, ParseError<'a>> {
     let (result, tail) = try!(parse_path(input));
     if tail.len() != 0 {
-        println!("\nTrailing garbage: {}", tail);
-        return Err("Trailing garbage after string");
+        return Err(ParseError { message: "Trailing garbage after string",
+                                at: tail });
     }
     return Ok(result);
 }
@@ -254,6 +495,20 @@ mod tests {
         assert!(parsenum("42.").unwrap().0 == super::Num::Floaty(42.0));
         assert!(parsenum("42.goat").unwrap().0 == super::Num::Floaty(42.0));
         assert!(parsenum("42.goat").unwrap().1 == "goat");
+        assert!(parsenum("1e3]").unwrap() == (super::Num::Floaty(1000.0), "]"));
+        assert!(parsenum("-.5").unwrap().0 == super::Num::Floaty(-0.5));
+        assert!(parsenum("7else").unwrap() == (super::Num::Inty(7), "else"));
+        assert!(parsenum("1700000000000").unwrap().0 ==
+                super::Num::Inty(1700000000000));
+        assert!(parsenum("-9223372036854775808").unwrap().0 ==
+                super::Num::Inty(i64::min_value()));
+        assert!(parsenum("9223372036854775808").unwrap().0 ==
+                super::Num::Unsigned(1 << 63));
+        assert!(parsenum("18446744073709551615").unwrap().0 ==
+                super::Num::Unsigned(u64::max_value()));
+        assert!(parsenum("18446744073709551616").is_err());
+        assert!(parsenum("-9223372036854775809").is_err());
+        assert!(parsenum(".").is_err());
     }
 
     #[test]
@@ -265,6 +520,10 @@ mod tests {
         assert!(if let RawItem::StrItem(s) = item { s == "foo" } else { false });
         let item = parse_item("'\\'\\\\\"'").unwrap().0;
         assert!(if let RawItem::StrItem(s) = item { s == "'\\\"" } else { false });
+        let item = parse_item("false").unwrap().0;
+        assert!(if let RawItem::BoolItem(b) = item { !b } else { false });
+        let item = parse_item("trueish").unwrap().0;
+        assert!(if let RawItem::Path(_) = item { true } else { false });
     }
 
     #[test]
@@ -273,9 +532,90 @@ mod tests {
         parse("foo.bar").unwrap();
         parse("foo.bar[baz = 42].quux").unwrap();
         parse("foo.bar['goat' = baz].quux").unwrap();
+        parse("stop[name ~ '^Main St']").unwrap();
+        parse("stop[name !~ 'x']").unwrap();
+        parse("entity[trip.route_id in ('A', 'B')]").unwrap();
+        parse("entity[stop_sequence in (1,2 , 3)]").unwrap();
+        assert!(parse("entity[trip.route_id in ('A', 'B']").is_err());
+        parse("entity[0]").unwrap();
+        parse("entity[-1]").unwrap();
+        parse("entity[2:5]").unwrap();
+        parse("entity[ -3 : ]").unwrap();
+        parse("entity[:-1]").unwrap();
+        parse("entity[:]").unwrap();
+        assert!(parse("entity[1.5:]").is_err());
+        // a map key, or an index out of range, depending on the field
+        parse("entity[4294967296]").unwrap();
+        parse("entity[18446744073709551615]").unwrap();
+        parse("vehicle[timestamp > 1700000000]").unwrap();
+        parse("position[speed <= 2.5]").unwrap();
+        parse("position[2.5>=speed]").unwrap();
+        parse("position[speed<2]").unwrap();
         assert!(parse("[bar]").is_err());
         assert!(parse("bar[]").is_err());
         assert!(parse("bar[@]").is_err());
-        assert!(parse("bar['foo']").is_err());
+        assert!(parse("bar[1.5]").is_err());
+        parse("vehicle[has(trip.route_id)]").unwrap();
+        parse("vehicle[exists( position ) and !has(trip)]").unwrap();
+        parse("vehicle[! exists(position)]").unwrap();
+        parse("vehicle[has = 1]").unwrap();
+        parse("entity[is_deleted = true]").unwrap();
+        parse("entity[false != is_deleted]").unwrap();
+        parse("entity[is_deleted]").unwrap();
+        parse("entity[not trip.is_added or is_deleted]").unwrap();
+        parse("attrs['x'].name").unwrap();
+        parse("event[which(payload) = 'click']").unwrap();
+        parse("event['view' != which( payload )]").unwrap();
+        parse("event[which(payload) in ('click', 'view')]").unwrap();
+        parse("event[which = 1]").unwrap();
+        parse("attrs[ * ].name").unwrap();
+        parse("attrs[*][0]").unwrap_err();
+        assert!(parse("vehicle[has(position]").is_err());
+        let p = parse("#1.#2[#3 = 42 and has(#4.name)]").unwrap();
+        assert_eq!(p[0].path, "#1");
+        assert_eq!(p[1].path, "#2");
+        parse("entity.#1000").unwrap();
+        assert!(parse("entity.#x").is_err());
+    }
+
+    #[test]
+    fn test_error_location() {
+        let q = "foo.bar[baz = 42 quux]";
+        let e = parse(q).unwrap_err();
+        assert_eq!(e.message, "couldn't find trailing ]");
+        assert_eq!(e.at, "quux]");
+        let e = parse("foo.bar!").unwrap_err();
+        assert_eq!(e.at, "!");
+        let e = parse("foo[bar in 'x']").unwrap_err();
+        assert_eq!(e.at, "'x']");
+
+        let p = parse("foo[ bar = 'x' ].baz").unwrap();
+        assert_eq!(p[0].filter_src, "bar = 'x'");
+        assert_eq!(p[1].filter_src, "");
+    }
+
+    #[test]
+    fn test_precedence() {
+        use super::RawFilter::*;
+        parse("entity[trip.route_id = 'A' and not (vehicle.stop_id in ('1','2')) or is_deleted = 1]").unwrap();
+        parse("entity[(a = 1 or b = 2) and c = 3 ]").unwrap();
+        parse("entity[not not a = 1]").unwrap();
+        parse("entity[android = 1 and order = 2]").unwrap();
+        assert!(parse("entity[a = 1 and]").is_err());
+        assert!(parse("entity[(a = 1]").is_err());
+        let p = parse("e[a = 1 or not b = 2 and c = 3]").unwrap();
+        match p[0].filter {
+            Or(ref l, ref r) => {
+                assert!(if let EqFilter(..) = **l { true } else { false });
+                match **r {
+                    And(ref l, _) =>
+                        assert!(if let Not(_) = **l { true } else { false }),
+                    _ => panic!("expected and"),
+                }
+            },
+            _ => panic!("expected or"),
+        }
+        let p = parse("e[(a = 1 or b = 2) and c = 3]").unwrap();
+        assert!(if let And(..) = p[0].filter { true } else { false });
     }
 }
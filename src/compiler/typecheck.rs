@@ -1,91 +1,404 @@
-use super::parser::{Path,RawFilter,RawItem};
-use ::query::{PBExpr,PBFilter, PBItem};
-use ::descriptors::{MessageDescriptor,FieldDescriptor,Label};
+use super::parser::{Path,PathPart,RawFilter,RawItem,Num};
+use ::query::{PBExpr,PBFilter, PBItem, PBPath, CmpOp};
+use ::descriptors::{MessageInfo,FieldInfo,EnumInfo,Label,Type,Schemaless};
+use ::CompileOptions;
 
 use std::collections::HashSet;
-extern crate libloading;
+use std::iter;
+use regex::bytes::Regex;
 
-type TypecheckResult<T> = Result<T, &'static str>;
+// at, when known, is the offending token in the query; otherwise the
+// error is reported against the whole filter it came from.
+#[derive(Debug)]
+pub struct TypeError<'a> {
+    pub message: &'static str,
+    pub at: Option<&'a str>,
+    pub field: Option<&'a str>,
+    pub message_type: Option<String>,
+    pub expected: Option<&'static str>,
+    pub actual: Option<Type>,
+    pub valid_values: Vec<String>,
+}
+
+impl<'a> TypeError<'a> {
+    fn mismatch(expected: &'static str, actual: Type, at: Option<&'a str>)
+                -> TypeError<'a> {
+        TypeError { message: "type mismatch", at: at, field: at,
+                    message_type: None,
+                    expected: Some(expected), actual: Some(actual),
+                    valid_values: vec!() }
+    }
+}
+
+impl<'a> From<&'static str> for TypeError<'a> {
+    fn from(message: &'static str) -> TypeError<'a> {
+        TypeError { message: message, at: None, field: None,
+                    message_type: None, expected: None, actual: None,
+                    valid_values: vec!() }
+    }
+}
+
+type TypecheckResult<'a, T> = Result<T, TypeError<'a>>;
+
+// What typechecking carries besides the schema: the options, and the
+// warnings found so far.
+pub struct Checker<'a> {
+    pub opts: &'a CompileOptions,
+    pub warnings: Vec<TypeError<'a>>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(opts: &'a CompileOptions) -> Checker<'a> {
+        Checker { opts: opts, warnings: vec!() }
+    }
+}
+
+// The names and numbers of the enum a path ends in, if it does.
+type EnumValues = Option<Vec<(String, i32)>>;
+
+fn enum_values<'d, F: FieldInfo<'d>>(field: F) -> EnumValues {
+    field.enum_type().map(|e| e.values().into_iter().map(
+        |(name, value)| (name.to_string(), value)).collect())
+}
+
+// Enum value names compare as their numbers.
+fn enum_value<'a>(item: RawItem<'a>, values: &EnumValues,
+                  at: Option<&'a str>) -> TypecheckResult<'a, RawItem<'a>> {
+    let (name, values) = match (item, values) {
+        (RawItem::StrItem(name), &Some(ref values)) => (name, values),
+        (item, _) => return Ok(item),
+    };
+    match values.iter().find(|v| v.0 == name) {
+        Some(v) => Ok(RawItem::IntItem(v.1 as i64)),
+        None => Err(TypeError {
+            message: "Unknown enum value", at: at, field: at,
+            message_type: None, expected: None, actual: None,
+            valid_values: values.iter().map(|v| v.0.clone()).collect() }),
+    }
+}
+
+// The last field name of a path, which is where type errors point.
+fn item_span<'a>(item: &RawItem<'a>) -> Option<&'a str> {
+    match item {
+        &RawItem::Path(ref p) => p.last().map(|part| part.path),
+        _ => None,
+    }
+}
+
+fn tc_path<'a, 'd, F>(item: RawItem<'a>, context: F, cx: &mut Checker<'a>)
+                      -> TypecheckResult<'a, (PBPath, Type)>
+    where F: FieldInfo<'d>
+{
+    tc_enum_path(item, context, cx).map(|(path, t, _)| (path, t))
+}
 
-fn tc_path(item: RawItem, context: &FieldDescriptor) ->
-    TypecheckResult<(PBItem, ::descriptors::Type)>
+// tc_path, plus the enum values if the path is an enum field.
+fn tc_enum_path<'a, 'd, F>(item: RawItem<'a>, context: F,
+                           cx: &mut Checker<'a>)
+                           -> TypecheckResult<'a, (PBPath, Type, EnumValues)>
+    where F: FieldInfo<'d>
 {
     let r = match item {
         RawItem::Path(p) => {
-            let md = context.get_message_descriptor();
+            let md = context.message_type();
             let fieldmessage = try!(md.ok_or("Not a message"));
-            let result = try!(typecheck(*p, fieldmessage));
+            let (result, values) = try!(typecheck_path(*p, fieldmessage, cx));
             let expr_type = result.expr_type;
-            (PBItem::Path(result), expr_type)
+            (PBPath::Path(result), expr_type, values)
         },
-        RawItem::AtItem => (PBItem::At, context.fieldtype),
-        _ => return Err("Expected path, found atom")
+        RawItem::AtItem =>
+            (PBPath::At, context.field_type(), enum_values(context)),
+        _ => return Err("Expected path, found atom".into())
     };
     Ok(r)
 }
 
-fn tc_atom(atom: RawItem) -> TypecheckResult<PBItem> {
+fn tc_atom<'a>(atom: RawItem<'a>) -> TypecheckResult<'a, PBItem> {
     Ok(match atom {
         RawItem::IntItem(i) => PBItem::Int(i),
+        RawItem::UintItem(u) => PBItem::Uint(u),
         RawItem::FloatItem(f) => PBItem::Float(f),
         RawItem::StrItem(s) => PBItem::Str(s),
-        _ => return Err("Expected atom, found path"),
+        RawItem::BoolItem(b) => PBItem::Bool(b),
+        _ => return Err("Expected atom, found path".into()),
     })
 }
 
-fn tc_eq(lhs: RawItem, rhs: RawItem, invert: bool,
-                context: &FieldDescriptor)
-                -> TypecheckResult<PBFilter> {
+fn tc_eq<'a, 'd, F>(lhs: RawItem<'a>, rhs: RawItem<'a>, invert: bool,
+                    context: F, cx: &mut Checker<'a>)
+                    -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    if let RawItem::Which(oneof) = lhs {
+        return tc_which(oneof, vec!(rhs), invert, context);
+    }
+    if let RawItem::Which(oneof) = rhs {
+        return tc_which(oneof, vec!(lhs), invert, context);
+    }
     if lhs.is_atom() && rhs.is_atom() {
-        return constant_fold(lhs, rhs, invert);
+        return Ok(fold_eq(lhs, rhs, invert));
+    }
+    if lhs.is_path() && rhs.is_path() {
+        let op = if invert { CmpOp::Ne } else { CmpOp::Eq };
+        return tc_path_cmp(lhs, rhs, op, context, cx);
     }
     let (rawpath, rawatom) = if lhs.is_path() {(lhs, rhs)} else {(rhs, lhs)};
 
-    let atom = try!(tc_atom(rawatom).
-                    or(Err("comparing two paths is not supported")));
-
-    let (path, pathtype) = try!(tc_path(rawpath, context));
+    let at = item_span(&rawpath);
+    let (path, pathtype, values) = try!(tc_enum_path(rawpath, context, cx));
+    let atom = try!(tc_atom(try!(enum_value(rawatom, &values, at))));
     match atom {
-        PBItem::Int(_) if pathtype.is_inty() => true,
-        PBItem::Float(_) if pathtype.is_floaty() => true,
-        PBItem::Str(_) if pathtype.is_stringy() => true,
-        _ => return Err("type mismatch"),
+        _ if pathtype == Type::UNKNOWN => (),
+        PBItem::Int(_) | PBItem::Uint(_)
+            if pathtype.is_inty() || pathtype.is_floaty() => (),
+        PBItem::Int(_) | PBItem::Uint(_) =>
+            return Err(TypeError::mismatch("an integer", pathtype, at)),
+        PBItem::Float(_) if pathtype.is_floaty() => (),
+        PBItem::Float(_) =>
+            return Err(TypeError::mismatch("a float", pathtype, at)),
+        PBItem::Str(_) if pathtype.is_stringy() => (),
+        PBItem::Str(_) =>
+            return Err(TypeError::mismatch("a string", pathtype, at)),
+        PBItem::Bool(_) if pathtype == Type::BOOL => (),
+        PBItem::Bool(_) =>
+            return Err(TypeError::mismatch("a boolean", pathtype, at)),
     };
        
-    Ok(PBFilter::EqFilter { atom: atom,
+    Ok(PBFilter::EqFilter { atom: promote(atom, pathtype),
                             path: path,
+                            pathtype: pathtype,
                             invert: invert })
 }
 
-fn constant_fold(lhs: RawItem, rhs: RawItem, invert: bool)
-                 -> Result<PBFilter, &'static str> {
+fn tc_rx<'a, 'd, F>(lhs: RawItem<'a>, rhs: RawItem<'a>, invert: bool,
+                    context: F, cx: &mut Checker<'a>)
+                    -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    let pattern = match rhs {
+        RawItem::StrItem(s) => s,
+        _ => return Err("right hand of a regex match must be a string".into()),
+    };
+    let at = item_span(&lhs);
+    let (path, pathtype) = try!(tc_path(lhs, context, cx));
+    if !pathtype.is_stringy() && pathtype != Type::UNKNOWN {
+        return Err(TypeError::mismatch("a string", pathtype, at));
+    }
+    let rx = try!(Regex::new(&pattern).or(Err("Invalid regular expression")));
+    Ok(PBFilter::RxFilter { rx: rx, path: path, invert: invert })
+}
+
+// Int literals compared against float fields are compared as floats.
+fn promote(atom: PBItem, pathtype: Type) -> PBItem {
+    match atom {
+        PBItem::Int(i) if pathtype.is_floaty() => PBItem::Float(i as f64),
+        PBItem::Uint(u) if pathtype.is_floaty() => PBItem::Float(u as f64),
+        a => a,
+    }
+}
+
+fn tc_cmp<'a, 'd, F>(lhs: RawItem<'a>, rhs: RawItem<'a>, op: CmpOp, context: F,
+                     cx: &mut Checker<'a>)
+                     -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    if lhs.is_atom() && rhs.is_atom() {
+        return fold_cmp(lhs, rhs, op);
+    }
+    if lhs.is_path() && rhs.is_path() {
+        return tc_path_cmp(lhs, rhs, op, context, cx);
+    }
+    let (rawpath, rawatom, op) = if lhs.is_path() { (lhs, rhs, op) }
+                                 else { (rhs, lhs, op.flip()) };
+
+    let atom = try!(tc_atom(rawatom));
+
+    let at = item_span(&rawpath);
+    let (path, pathtype) = try!(tc_path(rawpath, context, cx));
+    if !pathtype.is_inty() && !pathtype.is_floaty() &&
+        pathtype != Type::UNKNOWN
+    {
+        return Err(TypeError::mismatch("a number", pathtype, at));
+    }
+    match atom {
+        PBItem::Int(_) | PBItem::Uint(_) | PBItem::Float(_) => (),
+        _ => return Err("Ordering comparisons only support numbers".into()),
+    };
+
+    Ok(PBFilter::CmpFilter { atom: promote(atom, pathtype),
+                             path: path,
+                             pathtype: pathtype,
+                             op: op })
+}
+
+fn tc_path_cmp<'a, 'd, F>(lhs: RawItem<'a>, rhs: RawItem<'a>, op: CmpOp,
+                          context: F, cx: &mut Checker<'a>)
+                          -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    if let Some(w) = oneof_conflict(&lhs, &rhs, context) {
+        cx.warnings.push(w);
+    }
+    let (lat, rat) = (item_span(&lhs), item_span(&rhs));
+    let (lhs, lhstype, lhsvalues) = try!(tc_enum_path(lhs, context, cx));
+    let (rhs, rhstype, rhsvalues) = try!(tc_enum_path(rhs, context, cx));
+    let numeric = |t: Type| t != Type::ENUM && (t.is_inty() || t.is_floaty());
+    let ordered = op != CmpOp::Eq && op != CmpOp::Ne;
+    let ok = match (lhstype, rhstype) {
+        // schemaless values are compared as whatever their wire type says
+        (Type::UNKNOWN, _) | (_, Type::UNKNOWN) => true,
+        (l, r) if numeric(l) && numeric(r) => true,
+        _ if ordered => false,
+        (l, r) if l.is_stringy() && r.is_stringy() => true,
+        (Type::BOOL, Type::BOOL) => true,
+        // enums only compare with values of the same enum
+        (Type::ENUM, Type::ENUM) => lhsvalues == rhsvalues,
+        _ => false,
+    };
+    if ok {
+        return Ok(PBFilter::PathCmpFilter { lhs: lhs, lhstype: lhstype,
+                                            rhs: rhs, rhstype: rhstype,
+                                            op: op });
+    }
+    let expected = match lhstype {
+        _ if ordered => "a number",
+        t if numeric(t) => "a number",
+        t if t.is_stringy() => "a string",
+        Type::BOOL => "a boolean",
+        Type::ENUM => "a value of the same enum",
+        _ => "a scalar",
+    };
+    // point at the side that doesn't fit
+    let (actual, at) = if ordered && numeric(rhstype) { (lhstype, lat) }
+                       else { (rhstype, rat) };
+    Err(TypeError::mismatch(expected, actual, at))
+}
+
+// Members of the same oneof are never both set, so comparing two of them
+// never matches.
+fn oneof_conflict<'a, 'd, F>(lhs: &RawItem<'a>, rhs: &RawItem<'a>, context: F)
+                             -> Option<TypeError<'a>>
+    where F: FieldInfo<'d>
+{
+    let message = match context.message_type() {
+        Some(m) => m,
+        None => return None,
+    };
+    let first = |item: &RawItem<'a>| match item {
+        &RawItem::Path(ref p) => p.first().and_then(|part| {
+            let found = match field_number(part.path) {
+                Ok(Some(n)) => message.field_by_number(n),
+                Ok(None) => message.field_by_name(part.path),
+                Err(_) => None,
+            };
+            found.map(|f| (part.path, f))
+        }),
+        _ => None,
+    };
+    match (first(lhs), first(rhs)) {
+        (Some((_, l)), Some((at, r)))
+            if l.number() != r.number() && same_oneof(l, r) =>
+            Some(TypeError {
+                message: "Comparing two members of the same oneof, \
+                          which are never both set",
+                at: Some(at), field: Some(at),
+                message_type: Some(message.name().to_string()),
+                expected: None, actual: None, valid_values: vec!() }),
+        _ => None,
+    }
+}
+
+fn same_oneof<'d, F: FieldInfo<'d>>(l: F, r: F) -> bool {
+    match (l.oneof(), r.oneof()) {
+        (Some(a), Some(b)) => a == b,
+        _ => l.oneof_group().is_some() && l.oneof_group() == r.oneof_group(),
+    }
+}
+
+fn fold_cmp<'a>(lhs: RawItem<'a>, rhs: RawItem<'a>, op: CmpOp)
+                -> TypecheckResult<'a, PBFilter> {
+    let num = |item| match item {
+        RawItem::IntItem(i) => Ok(i as f64),
+        RawItem::UintItem(u) => Ok(u as f64),
+        RawItem::FloatItem(f) => Ok(f),
+        _ => Err("Ordering comparisons only support numbers"),
+    };
+    let (l, r) = (try!(num(lhs)), try!(num(rhs)));
+    Ok(const_filter(l.partial_cmp(&r).map_or(false, |o| op.test(o))))
+}
+
+fn const_filter(val: bool) -> PBFilter {
+    if val { PBFilter::TrueFilter } else { PBFilter::FalseFilter }
+}
+
+fn fold_eq(lhs: RawItem, rhs: RawItem, invert: bool) -> PBFilter {
     assert!(lhs.is_atom() && rhs.is_atom());
     let val = match (lhs, rhs) {
         (RawItem::IntItem(i1), RawItem::IntItem(i2)) => i1 == i2,
+        (RawItem::UintItem(u1), RawItem::UintItem(u2)) => u1 == u2,
         (RawItem::FloatItem(f1), RawItem::FloatItem(f2)) => f1 == f2,
         (RawItem::StrItem(s1), RawItem::StrItem(s2)) => s1 == s2,
+        (RawItem::BoolItem(b1), RawItem::BoolItem(b2)) => b1 == b2,
         _ => false
     };
-    if val && !invert || !val && invert {
-        Ok(PBFilter::TrueFilter)
-    } else {
-        Err("Constant folding produced false")
-    }
+    const_filter(val != invert)
 }
 
-fn tc_int_list(list: Vec<RawItem>) -> TypecheckResult<HashSet<i32>> {
-    if !list.iter().all(|i| i.is_int()) {
-        return Err("Expected a list of literal ints");
+// Simplify a filter tree whose leaves may have been folded to constants.
+fn constant_fold(filter: PBFilter) -> PBFilter {
+    match filter {
+        PBFilter::And(l, r) => match (constant_fold(*l), constant_fold(*r)) {
+            (PBFilter::FalseFilter, _) | (_, PBFilter::FalseFilter) =>
+                PBFilter::FalseFilter,
+            (PBFilter::TrueFilter, f) | (f, PBFilter::TrueFilter) => f,
+            (l, r) => PBFilter::And(Box::new(l), Box::new(r)),
+        },
+        PBFilter::Or(l, r) => match (constant_fold(*l), constant_fold(*r)) {
+            (PBFilter::TrueFilter, _) | (_, PBFilter::TrueFilter) =>
+                PBFilter::TrueFilter,
+            (PBFilter::FalseFilter, f) | (f, PBFilter::FalseFilter) => f,
+            (l, r) => PBFilter::Or(Box::new(l), Box::new(r)),
+        },
+        PBFilter::Not(f) => match constant_fold(*f) {
+            PBFilter::TrueFilter => PBFilter::FalseFilter,
+            PBFilter::FalseFilter => PBFilter::TrueFilter,
+            PBFilter::Not(f) => *f,
+            PBFilter::HasFilter { path, pathtype, zero_is_present, invert } =>
+                PBFilter::HasFilter { path: path,
+                                      pathtype: pathtype,
+                                      zero_is_present: zero_is_present,
+                                      invert: !invert },
+            f => PBFilter::Not(Box::new(f)),
+        },
+        f => f,
     }
-    Ok(list.into_iter().map(
-        |item| if let RawItem::IntItem(i) = item { i }
-        else { unreachable!() }
-    ).collect())
 }
 
-fn tc_str_list(list: Vec<RawItem>) -> TypecheckResult<HashSet<String>> {
+fn tc_has<'a, 'd, F>(rawitem: RawItem<'a>, invert: bool, context: F,
+                     cx: &mut Checker<'a>)
+                     -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    let (path, pathtype) = try!(tc_path(rawitem, context, cx));
+    Ok(PBFilter::HasFilter { path: path,
+                             pathtype: pathtype,
+                             zero_is_present: cx.opts.zero_is_present,
+                             invert: invert })
+}
+
+fn tc_int_list<'a>(list: Vec<RawItem<'a>>)
+                   -> TypecheckResult<'a, HashSet<i128>> {
+    list.into_iter().map(|item| match item {
+        RawItem::IntItem(i) => Ok(i as i128),
+        RawItem::UintItem(u) => Ok(u as i128),
+        _ => Err("Expected a list of literal ints".into()),
+    }).collect()
+}
+
+fn tc_str_list<'a>(list: Vec<RawItem<'a>>)
+                   -> TypecheckResult<'a, HashSet<String>> {
     if !list.iter().all(|i| i.is_str()) {
-        return Err("Expected a list of literal strings");
+        return Err("Expected a list of literal strings".into());
     }
     Ok(list.into_iter().map(
         |item| if let RawItem::StrItem(s) = item { s }
@@ -93,62 +406,634 @@ fn tc_str_list(list: Vec<RawItem>) -> TypecheckResult<HashSet<String>> {
     ).collect())
 }
 
-fn tc_in(rawitem: RawItem, list: Vec<RawItem>, context: &FieldDescriptor)
-                -> TypecheckResult<PBFilter> {
-    let (item, itype) = try!(tc_path(rawitem, context));
-    if itype.is_inty() {
+fn tc_in<'a, 'd, F>(rawitem: RawItem<'a>, list: Vec<RawItem<'a>>, context: F,
+                    cx: &mut Checker<'a>)
+                    -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    if let RawItem::Which(oneof) = rawitem {
+        return tc_which(oneof, list, false, context);
+    }
+    let at = item_span(&rawitem);
+    let (item, itype, values) = try!(tc_enum_path(rawitem, context, cx));
+    let list: Vec<_> = try!(list.into_iter()
+                            .map(|i| enum_value(i, &values, at)).collect());
+    // a schemaless field is whichever kind of list it's checked against
+    let itype = match (itype, list.first()) {
+        (Type::UNKNOWN, Some(&RawItem::StrItem(_))) => Type::BYTES,
+        (t, _) => t,
+    };
+    if itype.is_inty() || itype == Type::UNKNOWN {
         let l = try!(tc_int_list(list));
-        Ok(PBFilter::InIntFilter(item, l))
+        Ok(PBFilter::InIntFilter(item, itype, l))
     } else if itype.is_stringy() {
         let l = try!(tc_str_list(list));
         Ok(PBFilter::InStrFilter(item, l))
     } else {
-        Err("Operator 'in' only supports ints or strings")
+        Err(TypeError::mismatch("an integer or string", itype, at))
     }
 }
     
-fn tc_filter(rawfilter: RawFilter, context: &FieldDescriptor)
-                    -> Result<PBFilter, &'static str> {
+// which(oneof) compared with the names of its members.
+// The fields of message that are members of oneof.
+pub fn oneof_members<'a, 'd, M>(oneof: &'a str, message: M)
+                                -> TypecheckResult<'a, Vec<M::Field>>
+    where M: MessageInfo<'d>
+{
+    let members: Vec<M::Field> = message.fields().into_iter()
+        .filter(|f| f.oneof() == Some(oneof)).collect();
+    if members.is_empty() {
+        let unnamed = message.fields().into_iter()
+            .any(|f| f.oneof().is_none() && f.oneof_group().is_some());
+        return Err(TypeError {
+            message: if unnamed {
+                "Oneofs unsupported with protobuf-c descriptors"
+            } else {
+                "No such oneof"
+            },
+            at: Some(oneof), field: None,
+            message_type: Some(message.name().to_string()),
+            expected: None, actual: None, valid_values: vec!() });
+    }
+    Ok(members)
+}
+
+fn tc_which<'a, 'd, F>(oneof: &'a str, cases: Vec<RawItem<'a>>, invert: bool,
+                       context: F)
+                       -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    let message = try!(context.message_type().ok_or("Not a message"));
+    let members = try!(oneof_members(oneof, message));
+    let mut active = HashSet::new();
+    for case in cases {
+        let name = match case {
+            RawItem::StrItem(s) => s,
+            _ => return Err(
+                "which() can only be compared with member names".into()),
+        };
+        match members.iter().find(|f| f.name() == name) {
+            Some(f) => active.insert(f.number()),
+            None => return Err(TypeError {
+                message: "Unknown oneof member", at: Some(oneof), field: None,
+                message_type: Some(message.name().to_string()),
+                expected: None, actual: None,
+                valid_values: members.iter().map(
+                    |f| f.name().to_string()).collect() }),
+        };
+    }
+    let cases = members.iter().map(|f| f.number()).collect();
+    Ok(PBFilter::WhichFilter { cases: cases, active: active, invert: invert })
+}
+
+// The value field of a map field's entries, or None if it isn't a map.
+fn map_value<'d, F: FieldInfo<'d>>(field: F) -> Option<F> {
+    if field.label() != Label::REPEATED { return None }
+    match field.message_type() {
+        Some(entry) if entry.is_map_entry() => entry.field_by_number(2),
+        _ => None,
+    }
+}
+
+// A map lookup is a filter on the entries' keys.
+fn tc_key<'a, 'd, F>(key: RawItem<'a>, context: F, cx: &mut Checker<'a>)
+                     -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    if map_value(context).is_none() {
+        return Err("Key lookup on a field that isn't a map".into());
+    }
+    let keypath = vec!(PathPart { path: "#1", filter: RawFilter::TrueFilter,
+                                  filter_src: "" });
+    // report errors against the lookup, as the query never named the key
+    tc_eq(RawItem::Path(Box::new(keypath)), key, false, context, cx)
+        .map_err(|mut e| { e.at = None; e })
+}
+
+fn tc_filter<'a, 'd, F>(rawfilter: RawFilter<'a>, context: F,
+                        cx: &mut Checker<'a>)
+                        -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
     match rawfilter {
         RawFilter::TrueFilter => Ok(PBFilter::TrueFilter),
         RawFilter::EqFilter(lhs, rhs, inv) =>
-            tc_eq(lhs, rhs, inv, context),
-        /* Check that RHS is a string, LHS is a string message */
-        RawFilter::RxFilter(lhs, rhs, inv) => unimplemented!(),
-        RawFilter::InFilter(item, list) => tc_in(item, list, context),
-        RawFilter::IdxFilter(i) =>
-            if context.label == Label::REPEATED && i >= 0 {
-                Ok(PBFilter::IdxFilter(i as u32))
+            tc_eq(lhs, rhs, inv, context, cx),
+        RawFilter::RxFilter(lhs, rhs, inv) =>
+            tc_rx(lhs, rhs, inv, context, cx),
+        RawFilter::InFilter(item, list) => tc_in(item, list, context, cx),
+        RawFilter::CmpFilter(lhs, rhs, op) =>
+            tc_cmp(lhs, rhs, op, context, cx),
+        RawFilter::HasFilter(item, inv) => tc_has(item, inv, context, cx),
+        RawFilter::KeyFilter(key) => tc_key(key, context, cx),
+        RawFilter::IdxFilter(n) if map_value(context).is_some() =>
+            tc_key(n.into_item(), context, cx),
+        RawFilter::AllFilter =>
+            if context.label() == Label::REPEATED {
+                Ok(PBFilter::TrueFilter)
+            } else {
+                Err("Wildcard on a non-repeated field".into())
+            },
+        RawFilter::IdxFilter(n) =>
+            if context.label() == Label::REPEATED {
+                tc_index(n).map(PBFilter::IdxFilter)
+            } else {
+                Err("Indexing a non-repeated field".into())
+            },
+        RawFilter::SliceFilter(start, end) =>
+            if context.label() == Label::REPEATED {
+                Ok(PBFilter::SliceFilter(start, end))
             } else {
-                Err("bad index")
+                Err("Slicing a non-repeated field".into())
             },
+        RawFilter::And(l, r) =>
+            Ok(PBFilter::And(Box::new(try!(tc_operand(*l, context, cx))),
+                             Box::new(try!(tc_operand(*r, context, cx))))),
+        RawFilter::Or(l, r) =>
+            Ok(PBFilter::Or(Box::new(try!(tc_operand(*l, context, cx))),
+                            Box::new(try!(tc_operand(*r, context, cx))))),
+        RawFilter::Not(f) =>
+            Ok(PBFilter::Not(Box::new(try!(tc_operand(*f, context, cx))))),
+    }
+}
+
+// An index has to fit an i32; a bigger literal can only be a map key.
+fn tc_index<'a>(n: Num) -> TypecheckResult<'a, i32> {
+    match n {
+        Num::Inty(i) if i >= i32::min_value() as i64 &&
+                        i <= i32::max_value() as i64 => Ok(i as i32),
+        Num::Floaty(_) => Err("Index must be an integer".into()),
+        _ => Err("Index out of range".into()),
+    }
+}
+
+// Index filters select by position, so they can't be combined with
+// and/or/not.
+fn tc_operand<'a, 'd, F>(rawfilter: RawFilter<'a>, context: F,
+                         cx: &mut Checker<'a>)
+                         -> TypecheckResult<'a, PBFilter>
+    where F: FieldInfo<'d>
+{
+    match rawfilter {
+        RawFilter::IdxFilter(_) | RawFilter::SliceFilter(..) |
+        RawFilter::KeyFilter(_) | RawFilter::AllFilter =>
+            Err("Indexes can't be combined with other filters".into()),
+        f => tc_filter(f, context, cx),
+    }
+}
+
+// The number in a `#N` path part, or None for a field name.
+fn field_number<'a>(name: &'a str) -> TypecheckResult<'a, Option<u32>> {
+    if !name.starts_with('#') {
+        return Ok(None);
+    }
+    match name[1..].parse::<u32>() {
+        Ok(n) if n > 0 && n < 1 << 29 => Ok(Some(n)),
+        _ => Err(TypeError { message: "Invalid field number", at: Some(name),
+                             field: Some(name), message_type: None,
+                             expected: None, actual: None,
+                             valid_values: vec!() }),
     }
 }
 
-pub fn typecheck(rawpath: Path, rootmessage: &MessageDescriptor)
-             -> Result<PBExpr, &'static str> {
+pub fn typecheck<'a, 'd, M>(rawpath: Path<'a>, rootmessage: M,
+                            cx: &mut Checker<'a>)
+                            -> TypecheckResult<'a, PBExpr>
+    where M: MessageInfo<'d>
+{
+    typecheck_path(rawpath, rootmessage, cx).map(|(expr, _)| expr)
+}
+
+fn typecheck_path<'a, 'd, M>(rawpath: Path<'a>, rootmessage: M,
+                             cx: &mut Checker<'a>)
+                             -> TypecheckResult<'a, (PBExpr, EnumValues)>
+    where M: MessageInfo<'d>
+{
     let mut message = rootmessage;
+    let mut values = None;
     let mut paths = vec!();
     let mut filters = vec!();
     let mut types = vec!();
-    let len = rawpath.len();
-    for part in rawpath {
-        let field = message.get_field_by_name(part.path);
-        let f = try!(field.ok_or("No such field"));
-
-        filters.push(try!(tc_filter(part.filter, f)));
-
-        paths.push(f.id);
-        types.push(f.fieldtype);
-        match f.get_message_descriptor() {
-            Some(m) => message = m,
-            None => break,
+    let mut parts = rawpath.into_iter().peekable();
+    while let Some(part) = parts.next() {
+        let found = match try!(field_number(part.path)) {
+            Some(n) => message.field_by_number(n),
+            None => message.field_by_name(part.path),
+        };
+        let f = match found {
+            Some(f) => f,
+            // A number the schema doesn't know, such as an unknown field
+            // or an extension: the rest of the path is schemaless.
+            None if part.path.starts_with('#') => {
+                let rest = iter::once(part).chain(parts).collect();
+                let (tail, _) = try!(typecheck_path(rest, Schemaless, cx));
+                paths.extend(tail.path);
+                filters.extend(tail.filters);
+                types.push(tail.expr_type);
+                break;
+            },
+            None => return Err(TypeError {
+                message: "No such field", at: Some(part.path),
+                field: Some(part.path),
+                message_type: Some(message.name().to_string()),
+                expected: None, actual: None, valid_values: vec!() }),
+        };
+
+        // a key lookup or wildcard on a map yields the entries' values
+        let lookup = match part.filter {
+            RawFilter::KeyFilter(_) | RawFilter::IdxFilter(_) |
+            RawFilter::AllFilter => map_value(f),
+            _ => None,
+        };
+        let (name, src) = (part.path, part.filter_src);
+        let located = |mut e: TypeError<'a>| {
+            if e.at.is_none() {
+                e.at = Some(src);
+                e.field = Some(name);
+                e.message_type = Some(message.name().to_string());
+            }
+            e
+        };
+        let filter = try!(tc_filter(part.filter, f, cx).map_err(located));
+        let filter = constant_fold(filter);
+        if let PBFilter::FalseFilter = filter {
+            return Err(located("Constant folding produced false".into()));
+        }
+        filters.push(filter);
+
+        paths.push(f.number());
+        types.push(f.field_type());
+        let f = match lookup {
+            Some(value) => {
+                filters.push(PBFilter::TrueFilter);
+                paths.push(value.number());
+                types.push(value.field_type());
+                value
+            },
+            None => f,
+        };
+        values = enum_values(f);
+        match (f.message_type(), parts.peek()) {
+            (Some(m), _) => message = m,
+            (None, Some(next)) => return Err(TypeError {
+                message: "Not a message", at: Some(next.path),
+                field: Some(part.path),
+                message_type: Some(message.name().to_string()),
+                expected: Some("a message"), actual: Some(f.field_type()),
+                valid_values: vec!() }),
+            (None, None) => (),
         }
     };
 
-    if paths.len() != len {
-        return Err("Could not match some fields");
-    }
     let t = try!(types.last().ok_or("Empty path"));
-    Ok(PBExpr { path: paths, filters: filters, expr_type: *t})
+    Ok((PBExpr { path: paths, filters: filters, expr_type: *t,
+                 enum_values: values.clone() }, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::constant_fold;
+    use ::query::{query, PBExpr, PBFilter, PBItem, PBPath};
+    use ::descriptors::Type;
+    use ::descriptors::native::tests::{transit_pool, field_bytes, field_varint,
+                                       stop, feed_with};
+    use ::{compile, compile_with_warnings, compile_oneof, CompileOptions,
+           Schemaless};
+
+    fn contents(buf: &[u8], expr: &PBExpr) -> Vec<Vec<u8>> {
+        let mut out = vec!();
+        query(buf, expr, &mut |m| { out.push(m.contents.to_vec()); true })
+            .unwrap();
+        out
+    }
+
+    fn eq() -> PBFilter {
+        PBFilter::EqFilter { atom: PBItem::Int(1), path: PBPath::At,
+                             pathtype: Type::INT32, invert: false }
+    }
+    fn b(f: PBFilter) -> Box<PBFilter> { Box::new(f) }
+
+    #[test]
+    fn test_constant_fold() {
+        let f = constant_fold(PBFilter::And(b(PBFilter::TrueFilter), b(eq())));
+        assert!(if let PBFilter::EqFilter { .. } = f { true } else { false });
+
+        let f = constant_fold(PBFilter::And(b(eq()), b(PBFilter::FalseFilter)));
+        assert!(if let PBFilter::FalseFilter = f { true } else { false });
+
+        let f = constant_fold(PBFilter::Or(b(PBFilter::FalseFilter), b(eq())));
+        assert!(if let PBFilter::EqFilter { .. } = f { true } else { false });
+
+        let f = constant_fold(PBFilter::Or(
+            b(eq()), b(PBFilter::Not(b(PBFilter::FalseFilter)))));
+        assert!(if let PBFilter::TrueFilter = f { true } else { false });
+
+        // an inverted = is false when the field is missing, and not isn't
+        let f = constant_fold(PBFilter::Not(b(eq())));
+        assert!(if let PBFilter::Not(..) = f { true } else { false });
+        let which = PBFilter::WhichFilter { cases: vec!(1, 2),
+                                            active: vec!(1).into_iter()
+                                                .collect(),
+                                            invert: false };
+        let f = constant_fold(PBFilter::Not(b(which)));
+        assert!(if let PBFilter::Not(..) = f { true } else { false });
+
+        let f = constant_fold(PBFilter::Not(b(PBFilter::Not(b(
+            PBFilter::And(b(eq()), b(eq())))))));
+        assert!(if let PBFilter::And(..) = f { true } else { false });
+    }
+
+    #[test]
+    fn test_not_missing_field() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let mut a = stop("a", &[(2, 1)]);
+        a.extend(field_bytes(7, b"1"));
+        let mut c = stop("c", &[(2, 2)]);
+        c.extend(field_bytes(6, b"1"));
+        let buf = feed_with(&[a, stop("b", &[]), c]);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap()).concat();
+        assert_eq!(run("stops[not (id = 1)].name"), b"bc");
+        assert_eq!(run("stops[not (name ~ 'a')].name"), b"bc");
+        assert_eq!(run("stops[not (location.lat = 1.0)].name"), b"abc");
+        // != and an inverted which() are false for what's missing
+        assert_eq!(run("stops[id != 1].name"), b"c");
+        assert_eq!(run("stops[which(platform) != 'bay'].name"), b"c");
+        assert_eq!(run("stops[not which(platform) = 'bay'].name"), b"bc");
+    }
+
+    #[test]
+    fn test_path_cmp_types() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = feed_with(&[stop("a", &[(4, 1), (5, 1)]),
+                              stop("b", &[(5, 0)]), stop("c", &[])]);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap()).concat();
+        assert_eq!(run("stops[accessible = accessible].name"), b"ab");
+        assert_eq!(run("stops[accessible != accessible].name"), b"");
+        assert_eq!(run("stops[kind = kind].name"), b"a");
+
+        let mismatch = |q| {
+            let e = compile(q, feed).unwrap_err();
+            (e.expected.unwrap(), e.actual.unwrap())
+        };
+        assert_eq!(mismatch("stops[accessible = name]"),
+                   ("a boolean", Type::STRING));
+        assert_eq!(mismatch("stops[name = accessible]"),
+                   ("a string", Type::BOOL));
+        assert_eq!(mismatch("stops[kind = id]"),
+                   ("a value of the same enum", Type::INT32));
+        assert_eq!(mismatch("stops[id = kind]"), ("a number", Type::ENUM));
+        assert_eq!(mismatch("stops[accessible < accessible]"),
+                   ("a number", Type::BOOL));
+        assert_eq!(mismatch("stops[kind > kind]"), ("a number", Type::ENUM));
+    }
+
+    #[test]
+    fn test_uint64_literals() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = field_varint(2, u64::max_value());
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap()).len();
+        assert_eq!(run("timestamp[@ = 18446744073709551615]"), 1);
+        assert_eq!(run("timestamp[@ != 18446744073709551615]"), 0);
+        assert_eq!(run("timestamp[@ in (1, 18446744073709551615)]"), 1);
+        assert_eq!(run("timestamp[@ in (1, 18446744073709551614)]"), 0);
+        assert_eq!(run("timestamp[@ > 9223372036854775808]"), 1);
+        assert_eq!(run("timestamp[@ < 18446744073709551615]"), 0);
+        // a negative value is below any of them
+        let buf = feed_with(&[field_varint(2, (-1i64) as u64)]);
+        let expr = compile("stops[id < 9223372036854775808]", feed).unwrap();
+        assert_eq!(contents(&buf, &expr).len(), 1);
+        assert!(compile("stops[name = 18446744073709551615]", feed).is_err());
+    }
+
+    #[test]
+    fn test_compile_native() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let expr = compile("stops[name = 'b'].id", feed).unwrap();
+
+        let mut buf = feed_with(&[stop("a", &[(2, 1)]), stop("b", &[(2, 2)]),
+                                  stop("b", &[(2, 3)])]);
+        buf.extend(field_varint(2, 1500000000));
+
+        let mut ids = vec!();
+        query(&buf, &expr, &mut |m| {
+            ids.push(m.as_int(Type::INT32).unwrap());
+            true
+        }).unwrap();
+        assert_eq!(ids, vec!(2, 3));
+    }
+
+    #[test]
+    fn test_schemaless() {
+        // {1: {2: 42, 3: 1.5f, 4: "bus"}, 1: {2: 7, 4: "tram"}, 5: 1}
+        let mut first = field_varint(2, 42);
+        first.extend(&[0x1d, 0x00, 0x00, 0xc0, 0x3f]);
+        first.extend(field_bytes(4, b"bus"));
+        let mut second = field_varint(2, 7);
+        second.extend(field_bytes(4, b"tram"));
+        let mut buf = feed_with(&[first, second]);
+        buf.extend(field_varint(5, 1));
+
+        let run = |q| contents(&buf, &compile(q, Schemaless).unwrap());
+        assert_eq!(run("#1[#2 = 42].#4"), vec!(b"bus".to_vec()));
+        assert_eq!(run("#1[#3 > 1.0].#4"), vec!(b"bus".to_vec()));
+        assert_eq!(run("#1[#4 = 'tram'].#2"), vec!(vec!(7)));
+        assert_eq!(run("#1[#2 in (7, 8)].#4"), vec!(b"tram".to_vec()));
+        assert_eq!(run("#1[#4 ~ '^b'].#2"), vec!(vec!(42)));
+        assert_eq!(run("#1[#2 = 'bus']").len(), 0);
+        assert_eq!(run("#1[#4 > 3]").len(), 0);
+        assert_eq!(run("#1[-1].#4"), vec!(b"tram".to_vec()));
+        // a varint isn't a message, so there's nothing inside it
+        assert_eq!(run("#5.#1").len(), 0);
+        assert!(compile("#1.name", Schemaless).is_err());
+    }
+
+    #[test]
+    fn test_mixed_schema() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = feed_with(&[stop("a", &[(2, 1), (12, 5)]),
+                              stop("b", &[(2, 2), (12, 6)])]);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap());
+        assert_eq!(run("stops[#1 = 'b'].#2"), vec!(vec!(2)));
+        // field 12 isn't in the schema
+        let expr = compile("stops.#12", feed).unwrap();
+        assert_eq!(expr.expr_type, Type::UNKNOWN);
+        assert_eq!(run("stops[#12 = 5].name"), vec!(b"a".to_vec()));
+        assert_eq!(run("#1[name = 'b'].#12"), vec!(vec!(6)));
+        assert!(compile("stops[#1 > 3]", feed).is_err());
+    }
+
+    #[test]
+    fn test_enum_names() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = feed_with(&[stop("a", &[(4, 0)]), stop("b", &[(4, 1)]),
+                              stop("c", &[(4, 1)])]);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap());
+        assert_eq!(run("stops[kind = 'TRAM'].name"),
+                   vec!(b"b".to_vec(), b"c".to_vec()));
+        assert_eq!(run("stops[kind != 'TRAM'].name"), vec!(b"a".to_vec()));
+        assert_eq!(run("stops[kind = 0].name"), vec!(b"a".to_vec()));
+        assert_eq!(run("stops[kind in ('BUS', 1)]").len(), 3);
+        assert_eq!(run("stops.kind[@ = 'BUS']"), vec!(vec!(0)));
+
+        let e = compile("stops[kind = 'FERRY']", feed).unwrap_err();
+        assert_eq!(e.message, "Unknown enum value");
+        assert_eq!(e.span, 6..10);
+        assert_eq!(e.valid_values, vec!("BUS".to_string(), "TRAM".to_string()));
+        assert!(e.to_string().starts_with(
+            "Unknown enum value: expected one of BUS, TRAM (field `kind`)"));
+    }
+
+    #[test]
+    fn test_bool_fields() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = feed_with(&[stop("a", &[(5, 1)]), stop("b", &[(5, 0)]),
+                              stop("c", &[])]);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap());
+        assert_eq!(run("stops[accessible].name"), vec!(b"a".to_vec()));
+        assert_eq!(run("stops[accessible = true].name"), vec!(b"a".to_vec()));
+        assert_eq!(run("stops[accessible = false].name"),
+                   vec!(b"b".to_vec(), b"c".to_vec()));
+        assert_eq!(run("stops[not accessible].name"),
+                   vec!(b"b".to_vec(), b"c".to_vec()));
+        assert_eq!(run("stops[#5 = true].name"), vec!(b"a".to_vec()));
+
+        let e = compile("stops[name]", feed).unwrap_err();
+        assert_eq!(e.expected, Some("a boolean"));
+        assert_eq!(e.actual, Some(Type::STRING));
+        assert!(compile("stops[accessible = 1]", feed).is_err());
+        assert!(compile("stops[true = false]", feed).is_err());
+    }
+
+    #[test]
+    fn test_map_fields() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let mut buf = vec!();
+        for &(id, name) in &[("s1", "Main St"), ("s2", "Elm St")] {
+            let mut entry = field_bytes(1, id.as_bytes());
+            entry.extend(field_bytes(2, &stop(name, &[])));
+            buf.extend(field_bytes(5, &entry));
+        }
+        for &(line, name) in &[(7, "Red"), (9, "Blue")] {
+            let mut entry = field_varint(1, line);
+            entry.extend(field_bytes(2, name.as_bytes()));
+            buf.extend(field_bytes(6, &entry));
+        }
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap());
+        assert_eq!(run("stops_by_id['s2'].name"), vec!(b"Elm St".to_vec()));
+        assert_eq!(run("stops_by_id['s3'].name").len(), 0);
+        assert_eq!(run("stops_by_id[*].name"),
+                   vec!(b"Main St".to_vec(), b"Elm St".to_vec()));
+        assert_eq!(run("lines[9]"), vec!(b"Blue".to_vec()));
+        assert_eq!(run("lines[*]").len(), 2);
+        // other filters still see the entries
+        assert_eq!(run("stops_by_id[key = 's1'].value.name"),
+                   vec!(b"Main St".to_vec()));
+        assert_eq!(run("stops_by_id[value.name ~ '^E'].key"),
+                   vec!(b"s2".to_vec()));
+        assert_eq!(run("lines[key > 7].value"), vec!(b"Blue".to_vec()));
+        assert_eq!(run("stops[*].name").len(), 0);
+
+        let e = compile("lines['x']", feed).unwrap_err();
+        assert_eq!(e.field, Some("lines".to_string()));
+        assert_eq!(e.span, 6..9);
+        assert!(compile("stops['x']", feed).is_err());
+        assert!(compile("timestamp[*]", feed).is_err());
+    }
+
+    #[test]
+    fn test_map_wide_keys() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let mut buf = vec!();
+        let vehicles = [(5000000000i64, "V1"), (-5000000000, "V2"), (1, "V3")];
+        for &(id, name) in &vehicles {
+            let mut entry = field_varint(1, id as u64);
+            entry.extend(field_bytes(2, name.as_bytes()));
+            buf.extend(field_bytes(7, &entry));
+        }
+        for &(id, name) in &[(u64::max_value(), "D1"), (7, "D2")] {
+            let mut entry = field_varint(1, id);
+            entry.extend(field_bytes(2, name.as_bytes()));
+            buf.extend(field_bytes(8, &entry));
+        }
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap()).concat();
+        assert_eq!(run("vehicles[5000000000]"), b"V1");
+        assert_eq!(run("vehicles[-5000000000]"), b"V2");
+        assert_eq!(run("vehicles[1]"), b"V3");
+        assert_eq!(run("devices[18446744073709551615]"), b"D1");
+        assert_eq!(run("devices[7]"), b"D2");
+
+        let e = compile("stops[5000000000]", feed).unwrap_err();
+        assert_eq!(e.message, "Index out of range");
+        let e = compile("stops[18446744073709551615]", feed).unwrap_err();
+        assert_eq!(e.message, "Index out of range");
+    }
+
+    #[test]
+    fn test_oneof() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let platforms = [("a", Some(6), None), ("b", None, Some(7)),
+                         ("c", Some(6), Some(7)), ("d", None, None)];
+        let stops: Vec<_> = platforms.iter().map(|&(name, track, bay)| {
+            let mut s = stop(name, &[]);
+            for number in track.iter().chain(bay.iter()) {
+                s.extend(field_bytes(*number, b"1"));
+            }
+            s
+        }).collect();
+        let buf = feed_with(&stops);
+
+        let stop_type = pool.message("transit.Stop").unwrap();
+        let platform = compile_oneof("platform", stop_type).unwrap();
+        let set: Vec<_> = compile("stops", feed).unwrap().matches(&buf)
+            .map(|m| platform.which(m.unwrap().contents).unwrap()).collect();
+        assert_eq!(set, vec!(Some("track"), Some("bay"), Some("bay"), None));
+        let e = compile_oneof("gate", stop_type).unwrap_err();
+        assert_eq!(e.message, "No such oneof");
+        assert_eq!(e.span, 0..4);
+
+        let run = |q| contents(&buf, &compile(q, feed).unwrap()).concat();
+        assert_eq!(run("stops[which(platform) = 'bay'].name"), b"bc");
+        assert_eq!(run("stops['track' = which(platform)].name"), b"a");
+        assert_eq!(run("stops[which(platform) != 'bay'].name"), b"a");
+        assert_eq!(run("stops[not which(platform) = 'track'].name"), b"bcd");
+        assert_eq!(run("stops[which(platform) in ('track', 'bay')].name"),
+                   b"abc");
+
+        let e = compile("stops[which(gate) = 'x']", feed).unwrap_err();
+        assert_eq!(e.message, "No such oneof");
+        assert_eq!(e.span, 12..16);
+        let e = compile("stops[which(platform) = 'gate']", feed).unwrap_err();
+        assert_eq!(e.message, "Unknown oneof member");
+        assert_eq!(e.valid_values,
+                   vec!("track".to_string(), "bay".to_string()));
+        assert!(compile("stops[which(platform) = 1]", feed).is_err());
+
+        let opts = CompileOptions::default();
+        let (_, warnings) =
+            compile_with_warnings("stops[track = bay]", feed, &opts).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].span, 14..17);
+        assert_eq!(warnings[0].field, Some("bay".to_string()));
+        let (_, warnings) =
+            compile_with_warnings("stops[name = bay]", feed, &opts).unwrap();
+        assert!(warnings.is_empty());
+    }
 }
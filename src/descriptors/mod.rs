@@ -0,0 +1,111 @@
+// Schema information for the typechecker. Descriptors can come from
+// protobuf-c generated code, a serialized FileDescriptorSet or .proto
+// source; typecheck only sees them through the traits below, so other
+// registries (prost, rust-protobuf, ...) can be plugged in by
+// implementing MessageInfo, FieldInfo and EnumInfo for them.
+
+#[cfg(feature = "protobuf-c")]
+mod protobuf_c;
+pub mod native;
+mod proto;
+mod schemaless;
+
+#[cfg(feature = "protobuf-c")]
+pub use self::protobuf_c::{MessageDescriptor, FieldDescriptor, EnumDescriptor};
+pub use self::native::{DescriptorPool, PoolMessage, PoolField, PoolEnum,
+                       LoadError};
+pub use self::schemaless::{Schemaless, SchemalessField, NoEnum};
+
+#[repr(C)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Label { REQUIRED, OPTIONAL, REPEATED }
+
+#[repr(C)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Type {
+    INT32, SINT32, SFIXED32, INT64, SINT64, SFIXED64,
+    UINT32, FIXED32, UINT64, FIXED64,
+    FLOAT, DOUBLE,
+    BOOL,
+    ENUM,
+    STRING, BYTES,
+    MESSAGE,
+    // Not a protobuf-c type: a field queried by number with no schema,
+    // whose values are read according to their wire type.
+    UNKNOWN,
+}
+
+impl Type {
+    pub fn is_inty(self) -> bool {
+        match self {
+            Type::INT32 | Type::SINT32 | Type::SFIXED32 | Type::UINT32 |
+            Type::INT64 | Type::SINT64 | Type::SFIXED64 | Type::UINT64 |
+            Type::FIXED32 | Type::FIXED64 | Type::ENUM => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_floaty(self) -> bool {
+        match self {
+            Type::FLOAT | Type::DOUBLE => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_stringy(self) -> bool {
+        match self {
+            Type::STRING | Type::BYTES => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_message(self) -> bool {
+        if let Type::MESSAGE = self { true } else { false }
+    }
+}
+
+/// A message type that fields can be looked up in. Implementations are
+/// handles, usually references, so they're cheap to copy.
+pub trait MessageInfo<'a>: Copy {
+    type Field: FieldInfo<'a, Message = Self>;
+
+    /// The fully qualified name, e.g. "transit_realtime.FeedMessage".
+    fn name(self) -> &'a str;
+    fn field_by_name(self, name: &str) -> Option<Self::Field>;
+    fn field_by_number(self, number: u32) -> Option<Self::Field>;
+    /// Every field of the message.
+    fn fields(self) -> Vec<Self::Field>;
+    /// Whether this is the entry message behind a map field, with the
+    /// map's key as field 1 and its value as field 2.
+    fn is_map_entry(self) -> bool { false }
+}
+
+/// A field of a message type.
+pub trait FieldInfo<'a>: Copy {
+    type Message: MessageInfo<'a, Field = Self>;
+    type Enum: EnumInfo<'a>;
+
+    fn name(self) -> &'a str;
+    fn number(self) -> u32;
+    fn label(self) -> Label;
+    fn field_type(self) -> Type;
+    /// The field's message type, or None if it isn't a message.
+    fn message_type(self) -> Option<Self::Message>;
+    /// The field's enum type, or None if it isn't an enum.
+    fn enum_type(self) -> Option<Self::Enum>;
+    /// The name of the oneof the field is a member of, if any.
+    fn oneof(self) -> Option<&'a str> { None }
+    /// Identifies the field's oneof among its message's oneofs, for
+    /// descriptors that group oneof members without naming the oneof.
+    fn oneof_group(self) -> Option<u32> { None }
+}
+
+/// An enum type, for translating between value names and numbers.
+pub trait EnumInfo<'a>: Copy {
+    fn name(self) -> &'a str;
+    fn value_by_name(self, name: &str) -> Option<i32>;
+    /// The name of a value; the first one declared if it has aliases.
+    fn value_name(self, value: i32) -> Option<&'a str>;
+    /// Every value name and its number.
+    fn values(self) -> Vec<(&'a str, i32)>;
+}
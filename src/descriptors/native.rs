@@ -0,0 +1,618 @@
+// Descriptors decoded from a serialized FileDescriptorSet, as written by
+// `protoc --descriptor_set_out`. The tag numbers below are the ones in
+// google/protobuf/descriptor.proto. proto.rs fills the same pool from
+// .proto source.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use ::pbiter::{PBIter, DecodeError};
+use super::{Label, Type, MessageInfo, FieldInfo, EnumInfo};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    Decode(DecodeError),
+    /// A FieldDescriptorProto.Type this crate doesn't know.
+    UnknownFieldType(i64),
+    /// A field whose type_name matches no loaded message or enum.
+    UnresolvedType(String),
+    /// A .proto file that couldn't be read.
+    Io { file: String, message: String },
+    /// An import that isn't in any of the include paths.
+    ImportNotFound(String),
+    Syntax { file: String, line: usize, message: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Decode(ref e) => write!(f, "bad descriptor set: {}", e),
+            LoadError::UnknownFieldType(t) =>
+                write!(f, "unknown field type {}", t),
+            LoadError::UnresolvedType(ref name) =>
+                write!(f, "unresolved type name {}", name),
+            LoadError::Io { ref file, ref message } =>
+                write!(f, "{}: {}", file, message),
+            LoadError::ImportNotFound(ref name) =>
+                write!(f, "import {} not found", name),
+            LoadError::Syntax { ref file, line, ref message } =>
+                write!(f, "{}:{}: {}", file, line, message),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<DecodeError> for LoadError {
+    fn from(e: DecodeError) -> LoadError { LoadError::Decode(e) }
+}
+
+pub(crate) struct FieldData {
+    pub(crate) name: String,
+    pub(crate) number: u32,
+    pub(crate) label: Label,
+    pub(crate) fieldtype: Type,
+    pub(crate) type_name: String,
+    // Once type_name is resolved, an index into DescriptorPool::messages
+    // or DescriptorPool::enums, depending on fieldtype.
+    pub(crate) type_index: Option<usize>,
+    pub(crate) oneof: Option<String>,
+}
+
+struct MessageData {
+    name: String,
+    fields: Vec<FieldData>,
+    map_entry: bool,
+}
+
+struct EnumData {
+    name: String,
+    values: Vec<(String, i32)>,
+}
+
+/// A set of message types, looked up by fully qualified name.
+#[derive(Default)]
+pub struct DescriptorPool {
+    messages: Vec<MessageData>,
+    by_name: HashMap<String, usize>,
+    enums: Vec<EnumData>,
+    enums_by_name: HashMap<String, usize>,
+    // names of the files loaded so far, so imports are only read once
+    pub(crate) files: HashSet<String>,
+}
+
+// What a type name refers to.
+enum Named { Message(usize), Enum(usize) }
+
+pub(crate) fn qualify(scope: &str, name: &str) -> String {
+    if scope.is_empty() { name.to_string() }
+    else { format!("{}.{}", scope, name) }
+}
+
+fn type_from_proto(t: i64) -> Result<Type, LoadError> {
+    Ok(match t {
+        1 => Type::DOUBLE, 2 => Type::FLOAT,
+        3 => Type::INT64, 4 => Type::UINT64, 5 => Type::INT32,
+        6 => Type::FIXED64, 7 => Type::FIXED32,
+        8 => Type::BOOL, 9 => Type::STRING,
+        // groups are read like sub-messages
+        10 | 11 => Type::MESSAGE, 12 => Type::BYTES,
+        13 => Type::UINT32, 14 => Type::ENUM,
+        15 => Type::SFIXED32, 16 => Type::SFIXED64,
+        17 => Type::SINT32, 18 => Type::SINT64,
+        _ => return Err(LoadError::UnknownFieldType(t)),
+    })
+}
+
+// Also returns the index of the field's oneof in its message, if any.
+fn decode_field(buf: &[u8]) -> Result<(FieldData, Option<usize>), LoadError> {
+    let mut field = FieldData { name: String::new(), number: 0,
+                                label: Label::OPTIONAL, fieldtype: Type::MESSAGE,
+                                type_name: String::new(), type_index: None,
+                                oneof: None };
+    let mut fieldtype = 0;
+    let mut oneof = None;
+    let mut proto3_optional = false;
+    for m in PBIter::new(buf) {
+        let m = try!(m);
+        match m.tag {
+            1 => field.name = try!(m.as_str()).to_string(),
+            3 => field.number = try!(m.as_int(Type::INT32)) as u32,
+            4 => field.label = match try!(m.as_int(Type::ENUM)) {
+                2 => Label::REQUIRED,
+                3 => Label::REPEATED,
+                _ => Label::OPTIONAL,
+            },
+            5 => fieldtype = try!(m.as_int(Type::ENUM)),
+            6 => field.type_name = try!(m.as_str()).to_string(),
+            9 => oneof = Some(try!(m.as_int(Type::INT32)) as usize),
+            17 => proto3_optional = try!(m.as_int(Type::BOOL)) != 0,
+            _ => (),
+        }
+    }
+    field.fieldtype = try!(type_from_proto(fieldtype));
+    // proto3 optional fields are each alone in a made-up oneof
+    Ok((field, if proto3_optional { None } else { oneof }))
+}
+
+// OneofDescriptorProto.name
+fn decode_oneof(buf: &[u8]) -> Result<String, LoadError> {
+    let mut name = String::new();
+    for m in PBIter::new(buf) {
+        let m = try!(m);
+        if m.tag == 1 { name = try!(m.as_str()).to_string() }
+    }
+    Ok(name)
+}
+
+// MessageOptions.map_entry
+fn decode_map_entry(buf: &[u8]) -> Result<bool, LoadError> {
+    let mut map_entry = false;
+    for m in PBIter::new(buf) {
+        let m = try!(m);
+        if m.tag == 7 { map_entry = try!(m.as_int(Type::BOOL)) != 0 }
+    }
+    Ok(map_entry)
+}
+
+fn decode_enum_value(buf: &[u8]) -> Result<(String, i32), LoadError> {
+    let mut value = (String::new(), 0);
+    for m in PBIter::new(buf) {
+        let m = try!(m);
+        match m.tag {
+            1 => value.0 = try!(m.as_str()).to_string(),
+            2 => value.1 = try!(m.as_int(Type::INT32)) as i32,
+            _ => (),
+        }
+    }
+    Ok(value)
+}
+
+impl DescriptorPool {
+    pub fn new() -> DescriptorPool {
+        DescriptorPool::default()
+    }
+
+    pub fn from_descriptor_set(buf: &[u8]) -> Result<DescriptorPool, LoadError> {
+        let mut pool = DescriptorPool::new();
+        try!(pool.add_descriptor_set(buf));
+        Ok(pool)
+    }
+
+    /// Add every file in a serialized FileDescriptorSet. Type names are
+    /// resolved after the whole set is loaded, so the files can be in any
+    /// order.
+    pub fn add_descriptor_set(&mut self, buf: &[u8]) -> Result<(), LoadError> {
+        for file in PBIter::new(buf) {
+            let file = try!(file);
+            if file.tag == 1 {
+                try!(self.add_file(file.contents));
+            }
+        }
+        self.resolve()
+    }
+
+    fn add_file(&mut self, buf: &[u8]) -> Result<(), LoadError> {
+        let mut package = "";
+        let mut messages = vec!();
+        let mut enums = vec!();
+        for m in PBIter::new(buf) {
+            let m = try!(m);
+            match m.tag {
+                1 => { self.files.insert(try!(m.as_str()).to_string()); },
+                2 => package = try!(m.as_str()),
+                4 => messages.push(m.contents),
+                5 => enums.push(m.contents),
+                _ => (),
+            }
+        }
+        for e in enums {
+            try!(self.add_enum(package, e));
+        }
+        for m in messages {
+            try!(self.add_message(package, m));
+        }
+        Ok(())
+    }
+
+    fn add_message(&mut self, scope: &str, buf: &[u8]) -> Result<(), LoadError> {
+        let mut name = "";
+        let mut fields = vec!();
+        let mut nested = vec!();
+        let mut enums = vec!();
+        let mut map_entry = false;
+        let mut oneofs = vec!();
+        for m in PBIter::new(buf) {
+            let m = try!(m);
+            match m.tag {
+                1 => name = try!(m.as_str()),
+                2 => fields.push(try!(decode_field(m.contents))),
+                3 => nested.push(m.contents),
+                4 => enums.push(m.contents),
+                7 => map_entry = try!(decode_map_entry(m.contents)),
+                8 => oneofs.push(try!(decode_oneof(m.contents))),
+                _ => (),
+            }
+        }
+        let fields = fields.into_iter().map(|(mut f, oneof)| {
+            f.oneof = oneof.and_then(|i| oneofs.get(i).cloned());
+            f
+        }).collect();
+        let fullname = qualify(scope, name);
+        self.insert_message(fullname.clone(), fields, map_entry);
+        for e in enums {
+            try!(self.add_enum(&fullname, e));
+        }
+        for m in nested {
+            try!(self.add_message(&fullname, m));
+        }
+        Ok(())
+    }
+
+    fn add_enum(&mut self, scope: &str, buf: &[u8]) -> Result<(), LoadError> {
+        let mut name = "";
+        let mut values = vec!();
+        for m in PBIter::new(buf) {
+            let m = try!(m);
+            match m.tag {
+                1 => name = try!(m.as_str()),
+                2 => values.push(try!(decode_enum_value(m.contents))),
+                _ => (),
+            }
+        }
+        self.insert_enum(qualify(scope, name), values);
+        Ok(())
+    }
+
+    pub(crate) fn insert_message(&mut self, name: String,
+                                 fields: Vec<FieldData>, map_entry: bool) {
+        self.by_name.insert(name.clone(), self.messages.len());
+        self.messages.push(MessageData { name: name, fields: fields,
+                                         map_entry: map_entry });
+    }
+
+    pub(crate) fn insert_enum(&mut self, name: String,
+                              values: Vec<(String, i32)>) {
+        self.enums_by_name.insert(name.clone(), self.enums.len());
+        self.enums.push(EnumData { name: name, values: values });
+    }
+
+    fn find(&self, fullname: &str) -> Option<Named> {
+        if let Some(&i) = self.by_name.get(fullname) {
+            return Some(Named::Message(i));
+        }
+        self.enums_by_name.get(fullname).map(|&i| Named::Enum(i))
+    }
+
+    // Find a type name as written in a message called scope. Relative
+    // names are searched for from the innermost scope outwards, as protoc
+    // does.
+    fn lookup(&self, name: &str, scope: &str) -> Option<Named> {
+        if name.starts_with('.') {
+            return self.find(&name[1..]);
+        }
+        let mut scope = scope;
+        loop {
+            let found = self.find(&qualify(scope, name));
+            if found.is_some() {
+                return found;
+            }
+            if scope.is_empty() {
+                return None;
+            }
+            scope = scope.rfind('.').map_or("", |i| &scope[..i]);
+        }
+    }
+
+    // A .proto file doesn't say whether a named type is a message or an
+    // enum, so fields parsed from source start out as MESSAGE and turn
+    // into ENUM here if the name is an enum.
+    pub(crate) fn resolve(&mut self) -> Result<(), LoadError> {
+        for i in 0..self.messages.len() {
+            for j in 0..self.messages[i].fields.len() {
+                let resolved = {
+                    let m = &self.messages[i];
+                    let f = &m.fields[j];
+                    let named = f.fieldtype == Type::MESSAGE ||
+                        f.fieldtype == Type::ENUM && !f.type_name.is_empty();
+                    if !named || f.type_index.is_some() {
+                        continue;
+                    }
+                    match self.lookup(&f.type_name, &m.name) {
+                        Some(Named::Message(idx)) => (Type::MESSAGE, idx),
+                        Some(Named::Enum(idx)) => (Type::ENUM, idx),
+                        None => return Err(
+                            LoadError::UnresolvedType(f.type_name.clone())),
+                    }
+                };
+                let f = &mut self.messages[i].fields[j];
+                f.fieldtype = resolved.0;
+                f.type_index = Some(resolved.1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up a message by its fully qualified name, e.g.
+    /// "transit_realtime.FeedMessage".
+    pub fn message(&self, name: &str) -> Option<PoolMessage> {
+        let name = name.trim_left_matches('.');
+        self.by_name.get(name).map(
+            |&i| PoolMessage { pool: self, message: &self.messages[i] })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PoolMessage<'a> {
+    pool: &'a DescriptorPool,
+    message: &'a MessageData,
+}
+
+#[derive(Clone, Copy)]
+pub struct PoolField<'a> {
+    pool: &'a DescriptorPool,
+    field: &'a FieldData,
+}
+
+#[derive(Clone, Copy)]
+pub struct PoolEnum<'a> {
+    enumeration: &'a EnumData,
+}
+
+impl<'a> MessageInfo<'a> for PoolMessage<'a> {
+    type Field = PoolField<'a>;
+
+    fn name(self) -> &'a str { &self.message.name }
+    fn field_by_name(self, name: &str) -> Option<PoolField<'a>> {
+        self.message.fields.iter().find(|f| f.name == name).map(
+            |f| PoolField { pool: self.pool, field: f })
+    }
+    fn field_by_number(self, number: u32) -> Option<PoolField<'a>> {
+        self.message.fields.iter().find(|f| f.number == number).map(
+            |f| PoolField { pool: self.pool, field: f })
+    }
+    fn fields(self) -> Vec<PoolField<'a>> {
+        self.message.fields.iter().map(
+            |f| PoolField { pool: self.pool, field: f }).collect()
+    }
+    fn is_map_entry(self) -> bool { self.message.map_entry }
+}
+
+impl<'a> FieldInfo<'a> for PoolField<'a> {
+    type Message = PoolMessage<'a>;
+    type Enum = PoolEnum<'a>;
+
+    fn name(self) -> &'a str { &self.field.name }
+    fn number(self) -> u32 { self.field.number }
+    fn label(self) -> Label { self.field.label }
+    fn field_type(self) -> Type { self.field.fieldtype }
+    fn message_type(self) -> Option<PoolMessage<'a>> {
+        if self.field.fieldtype != Type::MESSAGE { return None }
+        self.field.type_index.map(
+            |i| PoolMessage { pool: self.pool, message: &self.pool.messages[i] })
+    }
+    fn enum_type(self) -> Option<PoolEnum<'a>> {
+        if self.field.fieldtype != Type::ENUM { return None }
+        self.field.type_index.map(
+            |i| PoolEnum { enumeration: &self.pool.enums[i] })
+    }
+    fn oneof(self) -> Option<&'a str> {
+        self.field.oneof.as_ref().map(|s| &s[..])
+    }
+}
+
+impl<'a> EnumInfo<'a> for PoolEnum<'a> {
+    fn name(self) -> &'a str { &self.enumeration.name }
+    fn value_by_name(self, name: &str) -> Option<i32> {
+        self.enumeration.values.iter().find(|v| v.0 == name).map(|v| v.1)
+    }
+    fn value_name(self, value: i32) -> Option<&'a str> {
+        self.enumeration.values.iter().find(|v| v.1 == value).map(|v| &v.0[..])
+    }
+    fn values(self) -> Vec<(&'a str, i32)> {
+        self.enumeration.values.iter().map(|v| (&v.0[..], v.1)).collect()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn varint(mut v: u64) -> Vec<u8> {
+        let mut out = vec!();
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+        out
+    }
+
+    pub fn field_varint(tag: u32, v: u64) -> Vec<u8> {
+        let mut out = varint((tag << 3) as u64);
+        out.extend(varint(v));
+        out
+    }
+
+    pub fn field_bytes(tag: u32, contents: &[u8]) -> Vec<u8> {
+        let mut out = varint((tag << 3 | 2) as u64);
+        out.extend(varint(contents.len() as u64));
+        out.extend(contents);
+        out
+    }
+
+    fn field(name: &str, number: u64, label: u64, t: u64, type_name: &str)
+             -> Vec<u8> {
+        let mut f = field_bytes(1, name.as_bytes());
+        f.extend(field_varint(3, number));
+        f.extend(field_varint(4, label));
+        f.extend(field_varint(5, t));
+        if !type_name.is_empty() {
+            f.extend(field_bytes(6, type_name.as_bytes()));
+        }
+        field_bytes(2, &f)
+    }
+
+    // package transit;
+    // message Stop {
+    //   message Location { double lat = 1; double lon = 2; }
+    //   enum Kind { BUS = 0; TRAM = 1; }
+    //   string name = 1;
+    //   int32 id = 2;
+    //   Location location = 3;
+    //   Kind kind = 4;
+    //   bool accessible = 5;
+    //   oneof platform { string track = 6; string bay = 7; }
+    //   bytes photo = 8;
+    //   repeated int64 line_ids = 9;
+    // }
+    // message Feed {
+    //   repeated Stop stops = 1;
+    //   uint64 timestamp = 2;
+    //   map<string, Stop> stops_by_id = 5;
+    //   map<int32, string> lines = 6;
+    //   map<int64, string> vehicles = 7;
+    //   map<uint64, string> devices = 8;
+    // }
+    pub fn transit_set() -> Vec<u8> {
+        let mut location = field_bytes(1, b"Location");
+        location.extend(field("lat", 1, 1, 1, ""));
+        location.extend(field("lon", 2, 1, 1, ""));
+
+        let mut kind = field_bytes(1, b"Kind");
+        for &(name, number) in &[("BUS", 0), ("TRAM", 1)] {
+            let mut value = field_bytes(1, name.as_bytes());
+            value.extend(field_varint(2, number));
+            kind.extend(field_bytes(2, &value));
+        }
+
+        let mut stop = field_bytes(1, b"Stop");
+        stop.extend(field("name", 1, 1, 9, ""));
+        stop.extend(field("id", 2, 1, 5, ""));
+        stop.extend(field("location", 3, 1, 11, "Location"));
+        stop.extend(field("kind", 4, 1, 14, ".transit.Stop.Kind"));
+        stop.extend(field("accessible", 5, 1, 8, ""));
+        for &(name, number) in &[("track", 6), ("bay", 7)] {
+            let mut f = field_bytes(1, name.as_bytes());
+            f.extend(field_varint(3, number));
+            f.extend(field_varint(4, 1));
+            f.extend(field_varint(5, 9));
+            f.extend(field_varint(9, 0));
+            stop.extend(field_bytes(2, &f));
+        }
+        stop.extend(field("photo", 8, 1, 12, ""));
+        stop.extend(field("line_ids", 9, 3, 3, ""));
+        stop.extend(field_bytes(8, &field_bytes(1, b"platform")));
+        stop.extend(field_bytes(3, &location));
+        stop.extend(field_bytes(4, &kind));
+
+        let mut feed = field_bytes(1, b"Feed");
+        feed.extend(field("stops", 1, 3, 11, ".transit.Stop"));
+        feed.extend(field("timestamp", 2, 1, 4, ""));
+        feed.extend(field("stops_by_id", 5, 3, 11,
+                          ".transit.Feed.StopsByIdEntry"));
+        feed.extend(field("lines", 6, 3, 11, ".transit.Feed.LinesEntry"));
+        feed.extend(field("vehicles", 7, 3, 11,
+                          ".transit.Feed.VehiclesEntry"));
+        feed.extend(field("devices", 8, 3, 11, ".transit.Feed.DevicesEntry"));
+        let entries = [("StopsByIdEntry", 9, 11, ".transit.Stop"),
+                       ("LinesEntry", 5, 9, ""),
+                       ("VehiclesEntry", 3, 9, ""),
+                       ("DevicesEntry", 4, 9, "")];
+        for &(entry, key, value, value_name) in &entries {
+            let mut m = field_bytes(1, entry.as_bytes());
+            m.extend(field("key", 1, 1, key, ""));
+            m.extend(field("value", 2, 1, value, value_name));
+            m.extend(field_bytes(7, &field_varint(7, 1)));
+            feed.extend(field_bytes(3, &m));
+        }
+
+        let mut file = field_bytes(1, b"transit.proto");
+        file.extend(field_bytes(2, b"transit"));
+        file.extend(field_bytes(4, &stop));
+        file.extend(field_bytes(4, &feed));
+        field_bytes(1, &file)
+    }
+
+    pub fn transit_pool() -> DescriptorPool {
+        DescriptorPool::from_descriptor_set(&transit_set()).unwrap()
+    }
+
+    // A transit.Stop with the given name, then the given varint fields.
+    pub fn stop(name: &str, varints: &[(u32, u64)]) -> Vec<u8> {
+        let mut out = field_bytes(1, name.as_bytes());
+        for &(tag, v) in varints {
+            out.extend(field_varint(tag, v));
+        }
+        out
+    }
+
+    // A transit.Feed holding the given stops.
+    pub fn feed_with(stops: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec!();
+        for s in stops {
+            out.extend(field_bytes(1, s));
+        }
+        out
+    }
+
+    #[test]
+    fn test_load() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        assert_eq!(feed.name(), "transit.Feed");
+        let stops = feed.field_by_name("stops").unwrap();
+        assert_eq!(stops.number(), 1);
+        assert_eq!(stops.label(), Label::REPEATED);
+        assert_eq!(stops.field_type(), Type::MESSAGE);
+        let stop = stops.message_type().unwrap();
+        assert_eq!(stop.name(), "transit.Stop");
+        assert_eq!(feed.field_by_name("timestamp").unwrap().field_type(),
+                   Type::UINT64);
+        assert!(feed.field_by_name("nope").is_none());
+        assert_eq!(feed.field_by_number(2).unwrap().name(), "timestamp");
+        assert!(feed.field_by_number(3).is_none());
+        assert!(stops.enum_type().is_none());
+
+        // relative type names resolve against the enclosing message
+        let location = stop.field_by_name("location").unwrap();
+        assert_eq!(location.message_type().unwrap().name(),
+                   "transit.Stop.Location");
+        assert!(pool.message(".transit.Stop.Location").is_some());
+        assert!(pool.message("Stop").is_none());
+
+        let kind = stop.field_by_name("kind").unwrap();
+        assert!(kind.message_type().is_none());
+        let kind = kind.enum_type().unwrap();
+        assert_eq!(kind.name(), "transit.Stop.Kind");
+        assert_eq!(kind.value_by_name("TRAM"), Some(1));
+        assert_eq!(kind.value_name(0), Some("BUS"));
+        assert_eq!(kind.value_name(7), None);
+
+        assert_eq!(stop.field_by_name("bay").unwrap().oneof(), Some("platform"));
+        assert_eq!(stop.field_by_name("name").unwrap().oneof(), None);
+        assert_eq!(stop.fields().len(), 9);
+
+        let entry = feed.field_by_name("lines").unwrap().message_type().unwrap();
+        assert_eq!(entry.name(), "transit.Feed.LinesEntry");
+        assert!(entry.is_map_entry());
+        assert!(!feed.is_map_entry());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut msg = field_bytes(1, b"M");
+        msg.extend(field("x", 1, 1, 11, ".nowhere.X"));
+        let file = field_bytes(4, &msg);
+        match DescriptorPool::from_descriptor_set(&field_bytes(1, &file)) {
+            Err(LoadError::UnresolvedType(ref n)) => assert_eq!(n, ".nowhere.X"),
+            _ => panic!("expected an unresolved type"),
+        }
+
+        let set = transit_set();
+        match DescriptorPool::from_descriptor_set(&set[..set.len() - 1]) {
+            Err(LoadError::Decode(DecodeError::LengthOverflow)) => (),
+            _ => panic!("expected a decode error"),
+        }
+    }
+}
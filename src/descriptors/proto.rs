@@ -0,0 +1,643 @@
+// A parser for .proto source, proto2 and proto3, that fills in a
+// DescriptorPool the same way a FileDescriptorSet does. Only what the
+// typechecker needs is kept: options, services and extensions are parsed
+// over and dropped.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::{Label, Type};
+use super::native::{DescriptorPool, FieldData, LoadError, qualify};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    // Identifiers include any dots, so `.foo.Bar` is one token.
+    Ident(&'a str),
+    Num(&'a str),
+    // The text between the quotes. Escapes are left as written.
+    Str(&'a str),
+    Sym(char),
+}
+
+fn describe(t: Option<Token>) -> String {
+    match t {
+        Some(Token::Ident(s)) | Some(Token::Num(s)) => format!("`{}`", s),
+        Some(Token::Str(s)) => format!("\"{}\"", s),
+        Some(Token::Sym(c)) => format!("`{}`", c),
+        None => "end of file".to_string(),
+    }
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'.'
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token, usize)>, (usize, &'static str)> {
+    let bytes = src.as_bytes();
+    let mut tokens = vec!();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).cloned().unwrap_or(0);
+        let start = i;
+        if c == b'\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c == b'/' && next == b'/' {
+            while i < bytes.len() && bytes[i] != b'\n' { i += 1 }
+        } else if c == b'/' && next == b'*' {
+            let end = try!(src[i + 2..].find("*/")
+                           .ok_or((line, "unterminated comment")));
+            line += src[i..i + 2 + end].matches('\n').count();
+            i += end + 4;
+        } else if c.is_ascii_alphabetic() || c == b'_' ||
+            c == b'.' && (next.is_ascii_alphabetic() || next == b'_')
+        {
+            while i < bytes.len() && is_ident_char(bytes[i]) { i += 1 }
+            tokens.push((Token::Ident(&src[start..i]), line));
+        } else if c.is_ascii_digit() || c == b'.' && next.is_ascii_digit() {
+            // covers hex, octal, floats and exponents like 1e-5
+            while i < bytes.len() && (is_ident_char(bytes[i]) ||
+                                      (bytes[i] == b'-' || bytes[i] == b'+') &&
+                                      (bytes[i - 1] == b'e' || bytes[i - 1] == b'E')) {
+                i += 1;
+            }
+            tokens.push((Token::Num(&src[start..i]), line));
+        } else if c == b'"' || c == b'\'' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != c {
+                if bytes[i] == b'\n' { break }
+                if bytes[i] == b'\\' { i += 1 }
+                i += 1;
+            }
+            if i >= bytes.len() || bytes[i] != c {
+                return Err((line, "unterminated string"));
+            }
+            tokens.push((Token::Str(&src[start + 1..i]), line));
+            i += 1;
+        } else if c.is_ascii() {
+            tokens.push((Token::Sym(c as char), line));
+            i += 1;
+        } else {
+            return Err((line, "unexpected character"));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        i64::from_str_radix(&s[2..], 16).ok()
+    } else if s.len() > 1 && s.starts_with('0') {
+        i64::from_str_radix(&s[1..], 8).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn scalar_type(name: &str) -> Option<Type> {
+    Some(match name {
+        "double" => Type::DOUBLE, "float" => Type::FLOAT,
+        "int32" => Type::INT32, "int64" => Type::INT64,
+        "uint32" => Type::UINT32, "uint64" => Type::UINT64,
+        "sint32" => Type::SINT32, "sint64" => Type::SINT64,
+        "fixed32" => Type::FIXED32, "fixed64" => Type::FIXED64,
+        "sfixed32" => Type::SFIXED32, "sfixed64" => Type::SFIXED64,
+        "bool" => Type::BOOL, "string" => Type::STRING, "bytes" => Type::BYTES,
+        _ => return None,
+    })
+}
+
+fn new_field(name: &str, number: i64, label: Label, typename: &str)
+             -> FieldData {
+    let (fieldtype, type_name) = match scalar_type(typename) {
+        Some(t) => (t, String::new()),
+        // resolved to a message or an enum once everything is loaded
+        None => (Type::MESSAGE, typename.to_string()),
+    };
+    FieldData { name: name.to_string(), number: number as u32, label: label,
+                fieldtype: fieldtype, type_name: type_name, type_index: None,
+                oneof: None }
+}
+
+// The name protoc gives the message behind a map field: "foo_bar" has
+// entries of type "FooBarEntry".
+fn map_entry_name(field: &str) -> String {
+    let mut name = String::new();
+    let mut upper = true;
+    for c in field.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            name.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            name.push(c);
+        }
+    }
+    name + "Entry"
+}
+
+struct ProtoParser<'a> {
+    file: &'a str,
+    tokens: Vec<(Token<'a>, usize)>,
+    pos: usize,
+}
+
+type ParseResult<T> = Result<T, LoadError>;
+
+impl<'a> ProtoParser<'a> {
+    fn new(file: &'a str, src: &'a str) -> ParseResult<ProtoParser<'a>> {
+        match tokenize(src) {
+            Ok(tokens) => Ok(ProtoParser { file: file, tokens: tokens, pos: 0 }),
+            Err((line, message)) => Err(LoadError::Syntax {
+                file: file.to_string(), line: line,
+                message: message.to_string() }),
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|t| t.0)
+    }
+
+    fn error<T>(&self, message: String) -> ParseResult<T> {
+        let line = self.tokens.get(self.pos).or(self.tokens.last())
+                       .map_or(1, |t| t.1);
+        Err(LoadError::Syntax { file: self.file.to_string(), line: line,
+                                message: message })
+    }
+
+    fn expected<T>(&self, what: &str) -> ParseResult<T> {
+        self.error(format!("expected {}, found {}", what, describe(self.peek())))
+    }
+
+    fn next(&mut self) -> ParseResult<Token<'a>> {
+        match self.peek() {
+            Some(t) => { self.pos += 1; Ok(t) },
+            None => self.error("unexpected end of file".to_string()),
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(Token::Sym(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> ParseResult<()> {
+        if self.eat(c) { Ok(()) } else { self.expected(&format!("`{}`", c)) }
+    }
+
+    fn ident(&mut self) -> ParseResult<&'a str> {
+        match self.peek() {
+            Some(Token::Ident(s)) => { self.pos += 1; Ok(s) },
+            _ => self.expected("an identifier"),
+        }
+    }
+
+    fn string(&mut self) -> ParseResult<&'a str> {
+        match self.peek() {
+            Some(Token::Str(s)) => { self.pos += 1; Ok(s) },
+            _ => self.expected("a string"),
+        }
+    }
+
+    fn int(&mut self) -> ParseResult<i64> {
+        let negative = self.eat('-');
+        match self.peek().and_then(|t| match t {
+            Token::Num(s) => parse_int(s),
+            _ => None,
+        }) {
+            Some(i) => { self.pos += 1; Ok(if negative { -i } else { i }) },
+            None => self.expected("an integer"),
+        }
+    }
+
+    // Skip to the end of a statement we don't care about: up to a `;`, or
+    // to the end of a `{ }` block.
+    fn skip_statement(&mut self) -> ParseResult<()> {
+        let mut depth = 0;
+        loop {
+            match try!(self.next()) {
+                Token::Sym(';') if depth == 0 => return Ok(()),
+                Token::Sym('{') | Token::Sym('[') | Token::Sym('(') => depth += 1,
+                Token::Sym('}') if depth == 1 => return Ok(()),
+                Token::Sym('}') | Token::Sym(']') | Token::Sym(')') => depth -= 1,
+                _ => (),
+            }
+        }
+    }
+
+    // `[packed = true, default = 3]` after a field or enum value
+    fn skip_options(&mut self) -> ParseResult<()> {
+        if !self.eat('[') {
+            return Ok(());
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match try!(self.next()) {
+                Token::Sym('[') => depth += 1,
+                Token::Sym(']') => depth -= 1,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the files this one imports.
+    fn file(&mut self, pool: &mut DescriptorPool) -> ParseResult<Vec<String>> {
+        let mut package = "";
+        let mut imports = vec!();
+        while let Some(t) = self.peek() {
+            self.pos += 1;
+            match t {
+                Token::Sym(';') => (),
+                Token::Ident("syntax") => {
+                    try!(self.expect('='));
+                    match try!(self.string()) {
+                        "proto2" | "proto3" => (),
+                        s => return self.error(
+                            format!("unsupported syntax \"{}\"", s)),
+                    }
+                    try!(self.expect(';'));
+                },
+                Token::Ident("package") => {
+                    package = try!(self.ident());
+                    try!(self.expect(';'));
+                },
+                Token::Ident("import") => {
+                    if let Some(Token::Ident("public")) |
+                           Some(Token::Ident("weak")) = self.peek() {
+                        self.pos += 1;
+                    }
+                    imports.push(try!(self.string()).to_string());
+                    try!(self.expect(';'));
+                },
+                Token::Ident("message") => try!(self.message(package, pool)),
+                Token::Ident("enum") => try!(self.enumeration(package, pool)),
+                Token::Ident("option") | Token::Ident("service") |
+                Token::Ident("extend") => try!(self.skip_statement()),
+                _ => {
+                    self.pos -= 1;
+                    return self.expected("a top-level definition");
+                },
+            }
+        }
+        Ok(imports)
+    }
+
+    fn message(&mut self, scope: &str, pool: &mut DescriptorPool)
+               -> ParseResult<()> {
+        let name = qualify(scope, try!(self.ident()));
+        try!(self.expect('{'));
+        let fields = try!(self.message_body(&name, pool));
+        pool.insert_message(name, fields, false);
+        Ok(())
+    }
+
+    fn message_body(&mut self, scope: &str, pool: &mut DescriptorPool)
+                    -> ParseResult<Vec<FieldData>> {
+        let mut fields = vec!();
+        loop {
+            let field = match try!(self.next()) {
+                Token::Sym('}') => return Ok(fields),
+                Token::Sym(';') => None,
+                Token::Ident("message") => {
+                    try!(self.message(scope, pool));
+                    None
+                },
+                Token::Ident("enum") => {
+                    try!(self.enumeration(scope, pool));
+                    None
+                },
+                Token::Ident("oneof") => {
+                    let name = try!(self.ident()).to_string();
+                    try!(self.expect('{'));
+                    fields.extend(try!(self.oneof(&name, scope, pool)));
+                    None
+                },
+                Token::Ident("option") | Token::Ident("reserved") |
+                Token::Ident("extensions") | Token::Ident("extend") => {
+                    try!(self.skip_statement());
+                    None
+                },
+                Token::Ident("map") if self.peek() == Some(Token::Sym('<')) =>
+                    Some(try!(self.map_field(scope, pool))),
+                Token::Ident("optional") =>
+                    Some(try!(self.field(Label::OPTIONAL, scope, pool))),
+                Token::Ident("required") =>
+                    Some(try!(self.field(Label::REQUIRED, scope, pool))),
+                Token::Ident("repeated") =>
+                    Some(try!(self.field(Label::REPEATED, scope, pool))),
+                // proto3 singular fields have no label
+                Token::Ident(_) => {
+                    self.pos -= 1;
+                    Some(try!(self.field(Label::OPTIONAL, scope, pool)))
+                },
+                _ => {
+                    self.pos -= 1;
+                    return self.expected("a field");
+                },
+            };
+            fields.extend(field);
+        }
+    }
+
+    fn oneof(&mut self, name: &str, scope: &str, pool: &mut DescriptorPool)
+             -> ParseResult<Vec<FieldData>> {
+        let mut fields = vec!();
+        loop {
+            match self.peek() {
+                Some(Token::Sym('}')) => { self.pos += 1; return Ok(fields) },
+                Some(Token::Sym(';')) => self.pos += 1,
+                Some(Token::Ident("option")) => try!(self.skip_statement()),
+                _ => {
+                    let mut field =
+                        try!(self.field(Label::OPTIONAL, scope, pool));
+                    field.oneof = Some(name.to_string());
+                    fields.push(field);
+                },
+            }
+        }
+    }
+
+    // A group defines a nested message and a field of that type, named
+    // like the group but in lower case.
+    fn field(&mut self, label: Label, scope: &str, pool: &mut DescriptorPool)
+             -> ParseResult<FieldData> {
+        let typename = try!(self.ident());
+        let name = try!(self.ident());
+        try!(self.expect('='));
+        let number = try!(self.int());
+        try!(self.skip_options());
+        if typename == "group" && self.eat('{') {
+            let group = qualify(scope, name);
+            let fields = try!(self.message_body(&group, pool));
+            pool.insert_message(group, fields, false);
+            return Ok(new_field(&name.to_lowercase(), number, label, name));
+        }
+        try!(self.expect(';'));
+        Ok(new_field(name, number, label, typename))
+    }
+
+    // map<K, V> name = N; is sugar for a repeated message with key and
+    // value fields, which is how it appears on the wire.
+    fn map_field(&mut self, scope: &str, pool: &mut DescriptorPool)
+                 -> ParseResult<FieldData> {
+        try!(self.expect('<'));
+        let key = try!(self.ident());
+        try!(self.expect(','));
+        let value = try!(self.ident());
+        try!(self.expect('>'));
+        let name = try!(self.ident());
+        try!(self.expect('='));
+        let number = try!(self.int());
+        try!(self.skip_options());
+        try!(self.expect(';'));
+
+        let entry = map_entry_name(name);
+        pool.insert_message(qualify(scope, &entry), vec!(
+            new_field("key", 1, Label::OPTIONAL, key),
+            new_field("value", 2, Label::OPTIONAL, value)), true);
+        Ok(new_field(name, number, Label::REPEATED, &entry))
+    }
+
+    fn enumeration(&mut self, scope: &str, pool: &mut DescriptorPool)
+                   -> ParseResult<()> {
+        let name = qualify(scope, try!(self.ident()));
+        let mut values = vec!();
+        try!(self.expect('{'));
+        loop {
+            match try!(self.next()) {
+                Token::Sym('}') => break,
+                Token::Sym(';') => (),
+                Token::Ident("option") | Token::Ident("reserved") =>
+                    try!(self.skip_statement()),
+                Token::Ident(value) => {
+                    try!(self.expect('='));
+                    values.push((value.to_string(), try!(self.int()) as i32));
+                    try!(self.skip_options());
+                    try!(self.expect(';'));
+                },
+                _ => {
+                    self.pos -= 1;
+                    return self.expected("an enum value");
+                },
+            }
+        }
+        pool.insert_enum(name, values);
+        Ok(())
+    }
+}
+
+fn read_source(path: &Path) -> Result<String, LoadError> {
+    let mut source = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+        Ok(_) => Ok(source),
+        Err(e) => Err(LoadError::Io { file: path.display().to_string(),
+                                      message: e.to_string() }),
+    }
+}
+
+impl DescriptorPool {
+    /// Parse a .proto file and everything it imports. Imports are looked
+    /// for in include_paths, or next to the file if there are none.
+    pub fn from_proto_file<P: AsRef<Path>>(path: P, include_paths: &[&Path])
+                                           -> Result<DescriptorPool, LoadError> {
+        let mut pool = DescriptorPool::new();
+        try!(pool.add_proto_file(path, include_paths));
+        Ok(pool)
+    }
+
+    pub fn add_proto_file<P: AsRef<Path>>(&mut self, path: P,
+                                          include_paths: &[&Path])
+                                          -> Result<(), LoadError> {
+        let path = path.as_ref();
+        let dir = [path.parent().unwrap_or(Path::new("."))];
+        let include_paths = if include_paths.is_empty() { &dir[..] }
+                            else { include_paths };
+        let source = try!(read_source(path));
+        try!(self.load_proto(&path.to_string_lossy(), &source, include_paths));
+        self.resolve()
+    }
+
+    /// Parse .proto source text. name is the file name used in errors and
+    /// to recognise the file if something imports it.
+    pub fn add_proto_source(&mut self, name: &str, source: &str,
+                            include_paths: &[&Path]) -> Result<(), LoadError> {
+        try!(self.load_proto(name, source, include_paths));
+        self.resolve()
+    }
+
+    fn load_proto(&mut self, name: &str, source: &str, include_paths: &[&Path])
+                  -> Result<(), LoadError> {
+        if !self.files.insert(name.to_string()) {
+            return Ok(());
+        }
+        let imports = try!(try!(ProtoParser::new(name, source)).file(self));
+        for import in imports {
+            if self.files.contains(&import) {
+                continue;
+            }
+            let path = include_paths.iter().map(|dir| dir.join(&import))
+                                    .find(|p| p.is_file());
+            let path = try!(path.ok_or(LoadError::ImportNotFound(import.clone())));
+            let source = try!(read_source(&path));
+            try!(self.load_proto(&import, &source, include_paths));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use ::descriptors::{MessageInfo, FieldInfo, EnumInfo};
+
+    const TRANSIT: &'static str = r#"
+        // A cut down gtfs-realtime.
+        syntax = "proto2";
+        package transit;
+        option java_package = "com.example.transit";
+
+        message Feed {
+          repeated Entity entity = 1;
+          optional uint64 timestamp = 2 [default = 0];
+          extensions 1000 to max;
+        }
+
+        /* An entity is a
+           trip or an alert. */
+        message Entity {
+          required string id = 1;
+          oneof kind {
+            Trip trip = 2;
+            Alert alert = 3;
+          }
+          map<string, int32> counts = 4;
+          optional group Legacy = 5 { optional int32 x = 1; }
+          message Alert {
+            enum Cause { UNKNOWN = 1; STRIKE = 2 [deprecated = true]; }
+            optional Cause cause = 1;
+            repeated string text = 2;
+          }
+        }
+
+        message Trip { optional string route = 1; optional sint32 delay = 2; }
+
+        service Feeds { rpc Get (Feed) returns (Feed) {} }
+    "#;
+
+    fn pool() -> DescriptorPool {
+        let mut pool = DescriptorPool::new();
+        pool.add_proto_source("transit.proto", TRANSIT, &[]).unwrap();
+        pool
+    }
+
+    #[test]
+    fn test_parse() {
+        let pool = pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let entity = feed.field_by_name("entity").unwrap();
+        assert_eq!(entity.label(), Label::REPEATED);
+        let entity = entity.message_type().unwrap();
+        assert_eq!(entity.name(), "transit.Entity");
+        assert_eq!(feed.field_by_name("timestamp").unwrap().field_type(),
+                   Type::UINT64);
+
+        // oneof members are ordinary fields
+        let trip = entity.field_by_name("trip").unwrap();
+        assert_eq!(trip.number(), 2);
+        assert_eq!(trip.message_type().unwrap().name(), "transit.Trip");
+        let alert = entity.field_by_name("alert").unwrap().message_type().unwrap();
+        assert_eq!(alert.name(), "transit.Entity.Alert");
+        assert_eq!(alert.field_by_name("cause").unwrap().field_type(), Type::ENUM);
+        let cause = alert.field_by_name("cause").unwrap().enum_type().unwrap();
+        assert_eq!(cause.name(), "transit.Entity.Alert.Cause");
+        assert_eq!(cause.value_by_name("STRIKE"), Some(2));
+        assert_eq!(cause.value_name(1), Some("UNKNOWN"));
+        assert_eq!(entity.field_by_number(3).unwrap().name(), "alert");
+        assert_eq!(trip.oneof(), Some("kind"));
+        assert_eq!(entity.field_by_name("id").unwrap().oneof(), None);
+        let members: Vec<_> = entity.fields().into_iter()
+            .filter(|f| f.oneof() == Some("kind")).map(|f| f.name()).collect();
+        assert_eq!(members, vec!("trip", "alert"));
+
+        let counts = entity.field_by_name("counts").unwrap();
+        assert_eq!(counts.label(), Label::REPEATED);
+        let entry = counts.message_type().unwrap();
+        assert_eq!(entry.name(), "transit.Entity.CountsEntry");
+        assert_eq!(entry.field_by_name("key").unwrap().field_type(), Type::STRING);
+        assert_eq!(entry.field_by_name("value").unwrap().field_type(), Type::INT32);
+        assert!(entry.is_map_entry());
+        assert!(!entity.is_map_entry());
+
+        let legacy = entity.field_by_name("legacy").unwrap();
+        assert_eq!(legacy.number(), 5);
+        assert_eq!(legacy.field_type(), Type::MESSAGE);
+        assert_eq!(legacy.message_type().unwrap().name(),
+                   "transit.Entity.Legacy");
+
+        let expr = ::compile("entity[trip.delay > 60].id", feed).unwrap();
+        assert_eq!(expr.path, vec!(1, 1));
+
+        // {entity: {id: "a", legacy: {x: 7}}}, with legacy as a group
+        let buf = b"\x0a\x07\x0a\x01a\x2b\x08\x07\x2c";
+        let expr = ::compile("entity[legacy.x = 7].legacy.x", feed).unwrap();
+        let mut xs = vec!();
+        ::query(buf, &expr, &mut |m| {
+            xs.push(m.as_int(Type::INT32).unwrap());
+            true
+        }).unwrap();
+        assert_eq!(xs, vec!(7));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let mut pool = DescriptorPool::new();
+        let e = pool.add_proto_source("bad.proto",
+                                      "syntax = \"proto3\";\n\
+                                       message M {\n  int32 x = ;\n}", &[]);
+        assert_eq!(e.unwrap_err(), LoadError::Syntax {
+            file: "bad.proto".to_string(), line: 3,
+            message: "expected an integer, found `;`".to_string() });
+
+        let e = pool.add_proto_source("nope.proto",
+                                      "message M { Missing m = 1; }", &[]);
+        assert_eq!(e.unwrap_err(), LoadError::UnresolvedType("Missing".to_string()));
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = env::temp_dir().join(format!("pbquery-proto-{}",
+                                               ::std::process::id()));
+        fs::create_dir_all(dir.join("common")).unwrap();
+        fs::write(dir.join("common/geo.proto"),
+                  "syntax = \"proto3\"; package geo;\n\
+                   message Point { double lat = 1; double lon = 2; }").unwrap();
+        fs::write(dir.join("stop.proto"),
+                  "syntax = \"proto3\"; package transit;\n\
+                   import public \"common/geo.proto\";\n\
+                   message Stop { string name = 1; geo.Point at = 2; }").unwrap();
+
+        let pool = DescriptorPool::from_proto_file(dir.join("stop.proto"), &[])
+            .unwrap();
+        let stop = pool.message("transit.Stop").unwrap();
+        let at = stop.field_by_name("at").unwrap().message_type().unwrap();
+        assert_eq!(at.name(), "geo.Point");
+
+        let e = DescriptorPool::from_proto_file(dir.join("stop.proto"),
+                                                &[&dir.join("common")]);
+        assert_eq!(e.err(),
+                   Some(LoadError::ImportNotFound("common/geo.proto".to_string())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
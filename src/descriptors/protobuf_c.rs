@@ -0,0 +1,320 @@
+extern crate libc;
+extern crate libloading;
+
+use std::ascii::*;
+use super::{Label, Type, MessageInfo, FieldInfo, EnumInfo};
+
+#[repr(C)]
+pub struct MessageDescriptor {
+    /** Magic value checked to ensure that the API is used correctly. */
+    magic: u32,
+    
+    /** The qualified name (e.g., "namespace.Type"). */
+    name: *const libc::c_char,
+    /** The unqualified name as given in the .proto file (e.g., "Type"). */
+    short_name: *const libc::c_char,
+    /** Identifier used in generated C code. */
+    c_name: *const libc::c_char,
+    /** The dot-separated namespace. */
+    package_name: *const libc::c_char,
+    sizeof_message: usize,
+
+    /** Number of elements in `fields`. */
+    n_fields: u32,
+    /** Field descriptors, sorted by tag number. */
+    fields: *const FieldDescriptor,
+    /** Used for looking up fields by name. */
+    fields_sorted_by_name: *const u32,
+    
+    /** Number of elements in `field_ranges`. */
+    n_field_ranges: u32,
+    /** Used for looking up fields by id. */
+    field_ranges: *const libc::c_void,
+    
+    message_init: *const libc::c_void,
+    reserved1: *const libc::c_void,
+    reserved2: *const libc::c_void,
+    reserved3: *const libc::c_void,
+}
+
+#[link(name = "protobuf-c")]
+extern {
+    fn protobuf_c_message_descriptor_get_field_by_name(
+        desc: *const MessageDescriptor,
+        name: *const libc::c_char) -> *const FieldDescriptor;
+    fn protobuf_c_message_descriptor_get_field(
+        desc: *const MessageDescriptor,
+        value: libc::c_uint) -> *const FieldDescriptor;
+    fn protobuf_c_enum_descriptor_get_value_by_name(
+        desc: *const EnumDescriptor,
+        name: *const libc::c_char) -> *const EnumValue;
+    fn protobuf_c_enum_descriptor_get_value(
+        desc: *const EnumDescriptor,
+        value: libc::c_int) -> *const EnumValue;
+}
+
+use std::ffi::{CStr, CString};
+use std::slice;
+impl MessageDescriptor {
+    pub fn load<'lib>(lib: &'lib libloading::Library, messagename: &str)
+                      -> Result<&'lib MessageDescriptor, &'static str> {
+
+        // "transit_realtime.FeedMessage" -> "transit_realtime__feed_message__descriptor"
+        let mut munged_messagename = String::new();
+
+        let mut prev = '\0';
+        for (i, c) in messagename.char_indices() {
+            if c == '.' {
+                munged_messagename.push('_');
+                munged_messagename.push('_');
+            } else if c.is_uppercase() {
+                if i > 0 && prev != '.' {
+                    munged_messagename.push('_');
+                }
+                let lc = c.to_ascii_lowercase();
+                munged_messagename.push(lc);
+            }
+            else {
+                munged_messagename.push(c);
+            }
+            prev = c;
+        }
+        let symname = format!("{}__descriptor", munged_messagename);
+        unsafe {
+            let s = lib.get(symname.as_bytes()).map(|x| *x);
+            s.map_err(|_| "Could not load symbol")
+        }
+    }
+    
+    /// The qualified name, e.g. "namespace.Type".
+    pub fn name(&self) -> &str {
+        if self.name.is_null() {
+            return "";
+        }
+        unsafe { CStr::from_ptr(self.name).to_str().unwrap_or("") }
+    }
+
+    pub fn get_field_by_name(&self, name: &str) ->
+        Option<&FieldDescriptor>
+    {
+        let cname = CString::new(name).unwrap();
+        unsafe {
+            protobuf_c_message_descriptor_get_field_by_name(
+                self, cname.as_ptr()).as_ref()
+        }
+    }
+
+    pub fn get_field(&self, id: u32) -> Option<&FieldDescriptor> {
+        unsafe {
+            protobuf_c_message_descriptor_get_field(self, id).as_ref()
+        }
+    }
+
+    /// The fields, sorted by number.
+    pub fn fields(&self) -> &[FieldDescriptor] {
+        if self.fields.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.fields, self.n_fields as usize) }
+    }
+}
+
+#[repr(C)]
+pub struct FieldDescriptor {
+    /** Name of the field as given in the .proto file. */
+    name: *const libc::c_char,
+    /** Tag value of the field as given in the .proto file. */
+    pub id: u32,
+    /** Whether the field is `REQUIRED`, `OPTIONAL`, or `REPEATED`. */
+    pub label: Label,
+    /** The type of the field. */
+    pub fieldtype: Type,
+    quantifier_offset: u32,
+    offset: u32,
+    /**
+     * A type-specific descriptor.
+     *
+     * If `type` is `PROTOBUF_C_TYPE_ENUM`, then `descriptor` points to the
+     * corresponding `ProtobufCEnumDescriptor`.
+     *
+     * If `type` is `PROTOBUF_C_TYPE_MESSAGE`, then `descriptor` points to
+     * the corresponding `ProtobufCMessageDescriptor`.
+     *
+     * Otherwise this field is NULL.
+     */
+    descriptor: *const libc::c_void, /* for MESSAGE and ENUM types */
+    /** The default value for this field, if defined. May be NULL. */
+    default_value: *const libc::c_void,
+    /**
+     * A flag word. Zero or more of the bits defined in the
+     * `ProtobufCFieldFlag` enum may be set.
+     */
+    flags: u32,
+
+    reserved_flags: u32,
+    reserved2: *const libc::c_void,
+    reserved3: *const libc::c_void,
+}
+
+impl FieldDescriptor {
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(self.name).to_str().unwrap_or("") }
+    }
+
+    pub fn get_message_descriptor(&self)
+                                  -> Option<&MessageDescriptor> {
+        if self.fieldtype != Type::MESSAGE { return None }
+        unsafe {
+            let desc = self.descriptor as *const MessageDescriptor;
+            desc.as_ref()
+        }
+    }
+
+    pub fn get_enum_descriptor(&self) -> Option<&EnumDescriptor> {
+        if self.fieldtype != Type::ENUM { return None }
+        unsafe {
+            let desc = self.descriptor as *const EnumDescriptor;
+            desc.as_ref()
+        }
+    }
+}
+
+#[repr(C)]
+pub struct EnumValue {
+    /** The string identifying this value in the .proto file. */
+    name: *const libc::c_char,
+    /** The string identifying this value in generated C code. */
+    c_name: *const libc::c_char,
+    /** The numeric value assigned in the .proto file. */
+    value: libc::c_int,
+}
+
+#[repr(C)]
+pub struct EnumDescriptor {
+    /** Magic value checked to ensure that the API is used correctly. */
+    magic: u32,
+
+    /** The qualified name (e.g., "namespace.Type"). */
+    name: *const libc::c_char,
+    /** The unqualified name as given in the .proto file (e.g., "Type"). */
+    short_name: *const libc::c_char,
+    /** Identifier used in generated C code. */
+    c_name: *const libc::c_char,
+    /** The dot-separated namespace. */
+    package_name: *const libc::c_char,
+
+    /** Number elements in `values`. */
+    n_values: libc::c_uint,
+    /** Array of distinct values, sorted by numeric value. */
+    values: *const EnumValue,
+
+    /** Number of elements in `values_by_name`. */
+    n_value_names: libc::c_uint,
+    /** Array of named values, including aliases, sorted by name. */
+    values_by_name: *const libc::c_void,
+
+    /** Number of elements in `value_ranges`. */
+    n_value_ranges: libc::c_uint,
+    /** Value ranges, for faster lookups by numeric value. */
+    value_ranges: *const libc::c_void,
+
+    reserved1: *const libc::c_void,
+    reserved2: *const libc::c_void,
+    reserved3: *const libc::c_void,
+    reserved4: *const libc::c_void,
+}
+
+impl EnumDescriptor {
+    pub fn name(&self) -> &str {
+        unsafe { CStr::from_ptr(self.name).to_str().unwrap_or("") }
+    }
+
+    pub fn get_value_by_name(&self, name: &str) -> Option<i32> {
+        let cname = match CString::new(name) {
+            Ok(s) => s,
+            Err(_) => return None,
+        };
+        unsafe {
+            protobuf_c_enum_descriptor_get_value_by_name(self, cname.as_ptr())
+                .as_ref().map(|v| v.value as i32)
+        }
+    }
+
+    pub fn values(&self) -> &[EnumValue] {
+        if self.values.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.values, self.n_values as usize) }
+    }
+
+    pub fn get_value(&self, value: i32) -> Option<&str> {
+        unsafe {
+            protobuf_c_enum_descriptor_get_value(self, value).as_ref().map(
+                |v| CStr::from_ptr(v.name).to_str().unwrap_or(""))
+        }
+    }
+}
+
+impl<'a> MessageInfo<'a> for &'a MessageDescriptor {
+    type Field = &'a FieldDescriptor;
+
+    fn name(self) -> &'a str { MessageDescriptor::name(self) }
+    fn field_by_name(self, name: &str) -> Option<&'a FieldDescriptor> {
+        self.get_field_by_name(name)
+    }
+    fn field_by_number(self, number: u32) -> Option<&'a FieldDescriptor> {
+        self.get_field(number)
+    }
+    fn fields(self) -> Vec<&'a FieldDescriptor> {
+        MessageDescriptor::fields(self).iter().collect()
+    }
+    // protobuf-c doesn't keep message options, so go by the shape protoc
+    // gives map entries: FooEntry { key = 1; value = 2; }
+    fn is_map_entry(self) -> bool {
+        let number = |name| self.get_field_by_name(name).map(|f| f.id);
+        self.name().ends_with("Entry") && self.n_fields == 2 &&
+            number("key") == Some(1) && number("value") == Some(2)
+    }
+}
+
+const PROTOBUF_C_FIELD_FLAG_ONEOF: u32 = 4;
+
+// protobuf-c flags oneof members but doesn't name their oneofs, so oneof()
+// stays None; members of one oneof share the offset of its case field.
+impl<'a> FieldInfo<'a> for &'a FieldDescriptor {
+    type Message = &'a MessageDescriptor;
+    type Enum = &'a EnumDescriptor;
+
+    fn name(self) -> &'a str { FieldDescriptor::name(self) }
+    fn number(self) -> u32 { self.id }
+    fn label(self) -> Label { self.label }
+    fn field_type(self) -> Type { self.fieldtype }
+    fn message_type(self) -> Option<&'a MessageDescriptor> {
+        self.get_message_descriptor()
+    }
+    fn enum_type(self) -> Option<&'a EnumDescriptor> {
+        self.get_enum_descriptor()
+    }
+    fn oneof_group(self) -> Option<u32> {
+        if self.flags & PROTOBUF_C_FIELD_FLAG_ONEOF != 0 {
+            Some(self.quantifier_offset)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> EnumInfo<'a> for &'a EnumDescriptor {
+    fn name(self) -> &'a str { EnumDescriptor::name(self) }
+    fn value_by_name(self, name: &str) -> Option<i32> {
+        self.get_value_by_name(name)
+    }
+    fn value_name(self, value: i32) -> Option<&'a str> {
+        self.get_value(value)
+    }
+    fn values(self) -> Vec<(&'a str, i32)> {
+        EnumDescriptor::values(self).iter().map(|v| unsafe {
+            (CStr::from_ptr(v.name).to_str().unwrap_or(""), v.value as i32)
+        }).collect()
+    }
+}
@@ -0,0 +1,51 @@
+// Descriptors for querying without a schema. Every field number exists,
+// may repeat and may be a message, and its values are read according to
+// their wire type: varints as int64, fixed-width values as signed ints or
+// floats depending on what they're compared with, and length-delimited
+// values as bytes, including packed repeated fields, which can't be told
+// apart from strings. Fields can only be named by number, as `#N`.
+//
+// Descending into a length-delimited field that turns out not to be a
+// message is a decode error, as it would be with a mistaken schema.
+
+use super::{Label, Type, MessageInfo, FieldInfo, EnumInfo};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Schemaless;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SchemalessField(u32);
+
+/// An enum type that can't exist, for descriptors without enums.
+#[derive(Clone, Copy, Debug)]
+pub enum NoEnum {}
+
+impl MessageInfo<'static> for Schemaless {
+    type Field = SchemalessField;
+
+    fn name(self) -> &'static str { "<schemaless>" }
+    fn field_by_name(self, _name: &str) -> Option<SchemalessField> { None }
+    fn field_by_number(self, number: u32) -> Option<SchemalessField> {
+        Some(SchemalessField(number))
+    }
+    fn fields(self) -> Vec<SchemalessField> { vec!() }
+}
+
+impl FieldInfo<'static> for SchemalessField {
+    type Message = Schemaless;
+    type Enum = NoEnum;
+
+    fn name(self) -> &'static str { "" }
+    fn number(self) -> u32 { self.0 }
+    fn label(self) -> Label { Label::REPEATED }
+    fn field_type(self) -> Type { Type::UNKNOWN }
+    fn message_type(self) -> Option<Schemaless> { Some(Schemaless) }
+    fn enum_type(self) -> Option<NoEnum> { None }
+}
+
+impl EnumInfo<'static> for NoEnum {
+    fn name(self) -> &'static str { match self {} }
+    fn value_by_name(self, _name: &str) -> Option<i32> { match self {} }
+    fn value_name(self, _value: i32) -> Option<&'static str> { match self {} }
+    fn values(self) -> Vec<(&'static str, i32)> { match self {} }
+}
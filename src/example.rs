@@ -1,3 +0,0 @@
-// fill buf
-// read the first varint from the result for length, consume that as a message
-
@@ -0,0 +1,318 @@
+// Rendering messages as proto3 JSON, following the canonical mapping in
+// the protobuf language guide, with a schema to name and type the fields.
+
+use std::collections::HashMap;
+use base64;
+use descriptors::{MessageInfo, FieldInfo, EnumInfo, Label, Type};
+use pbiter::{PBIter, PBMessage, DecodeResult, packed_wire_type};
+use query::{decode_value, occurrences, PBValue};
+
+/// Renders msg, a message of type message, as proto3 JSON: fields under
+/// their lowerCamelCase names in schema order, 64-bit integers as
+/// strings, enums by name and bytes as base64. Fields the schema doesn't
+/// know are left out.
+pub fn to_json<'d, M>(msg: &[u8], message: M) -> DecodeResult<String>
+    where M: MessageInfo<'d>
+{
+    let mut out = String::new();
+    try!(write_message(&mut out, msg, message));
+    Ok(out)
+}
+
+// protoc's default json_name: underscores dropped, and the letter after
+// each one capitalized.
+fn json_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Infinities and NaN, which JSON numbers can't hold, are strings.
+fn write_float(out: &mut String, v: f64, repr: String) {
+    if v.is_nan() {
+        out.push_str("\"NaN\"");
+    } else if v.is_infinite() {
+        out.push_str(if v > 0.0 { "\"Infinity\"" } else { "\"-Infinity\"" });
+    } else {
+        out.push_str(repr.trim_end_matches(".0"));
+    }
+}
+
+// 64-bit integers are strings, as they may not fit in a double.
+fn is_64_bit(t: Type) -> bool {
+    match t {
+        Type::INT64 | Type::SINT64 | Type::SFIXED64 |
+        Type::UINT64 | Type::FIXED64 => true,
+        _ => false,
+    }
+}
+
+fn write_value<'d, F>(out: &mut String, m: &PBMessage, field: F)
+                      -> DecodeResult<()>
+    where F: FieldInfo<'d>
+{
+    let t = field.field_type();
+    match try!(decode_value(m, t)) {
+        PBValue::I64(i) if is_64_bit(t) => write_str(out, &i.to_string()),
+        PBValue::U64(u) if is_64_bit(t) => write_str(out, &u.to_string()),
+        PBValue::I64(i) => out.push_str(&i.to_string()),
+        PBValue::U64(u) => out.push_str(&u.to_string()),
+        PBValue::F32(f) => write_float(out, f as f64, format!("{:?}", f)),
+        PBValue::F64(f) => write_float(out, f, format!("{:?}", f)),
+        PBValue::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        PBValue::Str(s) => write_str(out, s),
+        PBValue::Bytes(b) => {
+            out.push('"');
+            base64::encode(b, out);
+            out.push('"');
+        },
+        // a number the enum doesn't define stays a number
+        PBValue::Enum { number, .. } =>
+            match field.enum_type().and_then(|e| e.value_name(number)) {
+                Some(name) => write_str(out, name),
+                None => out.push_str(&number.to_string()),
+            },
+        PBValue::Message(b) => match field.message_type() {
+            Some(message) => try!(write_message(out, b, message)),
+            None => out.push_str("{}"),
+        },
+    }
+    Ok(())
+}
+
+// The value a field has when it isn't on the wire.
+fn write_default<'d, F>(out: &mut String, field: F)
+    where F: FieldInfo<'d>
+{
+    let t = field.field_type();
+    out.push_str(match t {
+        Type::MESSAGE => "{}",
+        Type::STRING | Type::BYTES => "\"\"",
+        Type::BOOL => "false",
+        Type::ENUM => match field.enum_type().and_then(|e| e.value_name(0)) {
+            Some(name) => return write_str(out, name),
+            None => "0",
+        },
+        t if is_64_bit(t) => "\"0\"",
+        _ => "0",
+    });
+}
+
+fn message_contents<'a>(m: &PBMessage<'a>) -> DecodeResult<&'a [u8]> {
+    match try!(decode_value(m, Type::MESSAGE)) {
+        PBValue::Message(b) => Ok(b),
+        _ => unreachable!(),
+    }
+}
+
+// A map's keys are always strings in JSON.
+fn map_key(key: Option<PBMessage>, t: Type) -> DecodeResult<String> {
+    let key = match key {
+        Some(k) => k,
+        None => return Ok(match t {
+            Type::STRING => "",
+            Type::BOOL => "false",
+            _ => "0",
+        }.to_string()),
+    };
+    Ok(match try!(decode_value(&key, t)) {
+        PBValue::Str(s) => s.to_string(),
+        PBValue::I64(i) => i.to_string(),
+        PBValue::U64(u) => u.to_string(),
+        PBValue::Bool(b) => b.to_string(),
+        // not a valid key type
+        _ => String::new(),
+    })
+}
+
+fn write_map<'d, F>(out: &mut String, entries: &[PBMessage], key_field: F,
+                    value_field: F) -> DecodeResult<()>
+    where F: FieldInfo<'d>
+{
+    // a later entry for a key replaces an earlier one
+    let mut rendered: Vec<(String, String)> = vec!();
+    for e in entries {
+        let (mut key, mut value) = (None, None);
+        for m in PBIter::new(try!(message_contents(e))) {
+            let m = try!(m);
+            if m.tag == 1 { key = Some(m) }
+            if m.tag == 2 { value = Some(m) }
+        }
+        let key = try!(map_key(key, key_field.field_type()));
+        let mut v = String::new();
+        match value {
+            Some(m) => try!(write_value(&mut v, &m, value_field)),
+            None => write_default(&mut v, value_field),
+        }
+        rendered.retain(|r| r.0 != key);
+        rendered.push((key, v));
+    }
+
+    out.push('{');
+    for (i, &(ref key, ref value)) in rendered.iter().enumerate() {
+        if i > 0 { out.push(',') }
+        write_str(out, key);
+        out.push(':');
+        out.push_str(value);
+    }
+    out.push('}');
+    Ok(())
+}
+
+fn write_message<'d, M>(out: &mut String, msg: &[u8], message: M)
+                        -> DecodeResult<()>
+    where M: MessageInfo<'d>
+{
+    let mut fields: HashMap<u32, Vec<PBMessage>> = HashMap::new();
+    for m in PBIter::new(msg) {
+        let m = try!(m);
+        fields.entry(m.tag).or_insert_with(Vec::new).push(m);
+    }
+
+    out.push('{');
+    let mut first = true;
+    for f in message.fields() {
+        let found = match fields.get(&f.number()) {
+            Some(found) => found,
+            None => continue,
+        };
+        if !first { out.push(',') }
+        first = false;
+        write_str(out, &json_name(f.name()));
+        out.push(':');
+
+        let t = f.field_type();
+        let map = f.message_type().filter(|m| m.is_map_entry()).and_then(
+            |m| m.field_by_number(1).and_then(
+                |k| m.field_by_number(2).map(|v| (k, v))));
+        if f.label() == Label::REPEATED {
+            if let Some((k, v)) = map {
+                try!(write_map(out, found, k, v));
+                continue;
+            }
+            out.push('[');
+            let mut first = true;
+            for &m in found {
+                for e in occurrences(m, packed_wire_type(t)) {
+                    if !first { out.push(',') }
+                    first = false;
+                    try!(write_value(out, &try!(e), f));
+                }
+            }
+            out.push(']');
+        } else if t.is_message() && found.len() > 1 {
+            // occurrences of a message field are merged, which is the
+            // same as reading their contents one after another
+            let mut merged = vec!();
+            for m in found {
+                merged.extend(try!(message_contents(m)));
+            }
+            try!(write_message(out, &merged, f.message_type().unwrap()));
+        } else {
+            // the last occurrence of a scalar wins
+            try!(write_value(out, found.last().unwrap(), f));
+        }
+    }
+    out.push('}');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use descriptors::native::tests::{transit_pool, field_bytes, field_varint,
+                                     stop, feed_with};
+
+    fn field_double(tag: u32, v: f64) -> Vec<u8> {
+        let mut out = vec![(tag << 3 | 1) as u8];
+        out.extend((0..8).map(|i| (v.to_bits() >> i * 8) as u8));
+        out
+    }
+
+    #[test]
+    fn test_json_name() {
+        assert_eq!(json_name("stops_by_id"), "stopsById");
+        assert_eq!(json_name("name"), "name");
+        assert_eq!(json_name("line__ids_"), "lineIds");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let stop_type = pool.message("transit.Stop").unwrap();
+
+        let mut location = field_double(1, 51.5);
+        location.extend(field_double(2, -0.125));
+        let mut main = stop("Main \"St\"\n", &[(2, (-3i64) as u64)]);
+        main.extend(field_bytes(3, &location));
+        main.extend(field_varint(4, 1));
+        main.extend(field_varint(5, 1));
+        main.extend(field_bytes(7, b"B"));
+        main.extend(field_bytes(8, b"\x00\xff"));
+        // packed, then not
+        main.extend(field_bytes(9, b"\x01\x02"));
+        main.extend(field_varint(9, 3));
+        // an unknown field
+        main.extend(field_varint(15, 1));
+        assert_eq!(to_json(&main, stop_type).unwrap(),
+                   "{\"name\":\"Main \\\"St\\\"\\n\",\"id\":-3,\
+                    \"location\":{\"lat\":51.5,\"lon\":-0.125},\
+                    \"kind\":\"TRAM\",\"accessible\":true,\"bay\":\"B\",\
+                    \"photo\":\"AP8=\",\"lineIds\":[\"1\",\"2\",\"3\"]}");
+
+        let mut buf = feed_with(&[field_varint(4, 7)]);
+        buf.extend(field_varint(2, 1500000000));
+        let mut entry = field_bytes(1, b"x");
+        entry.extend(field_bytes(2, &stop("a", &[])));
+        buf.extend(field_bytes(5, &entry));
+        buf.extend(field_bytes(5, &field_bytes(1, b"y")));
+        // a later entry for the same key wins
+        buf.extend(field_bytes(5, &field_bytes(1, b"x")));
+        let mut line = field_varint(1, 4);
+        line.extend(field_bytes(2, b"North"));
+        buf.extend(field_bytes(6, &line));
+        assert_eq!(to_json(&buf, feed).unwrap(),
+                   "{\"stops\":[{\"kind\":7}],\"timestamp\":\"1500000000\",\
+                    \"stopsById\":{\"y\":{},\"x\":{}},\
+                    \"lines\":{\"4\":\"North\"}}");
+
+        // a singular message on the wire twice is merged
+        let mut merged = field_bytes(3, &field_double(1, 1.0));
+        merged.extend(field_bytes(3, &field_double(2, 2.5)));
+        assert_eq!(to_json(&merged, stop_type).unwrap(),
+                   "{\"location\":{\"lat\":1,\"lon\":2.5}}");
+
+        let nan = field_bytes(3, &field_double(1, ::std::f64::NAN));
+        assert_eq!(to_json(&nan, stop_type).unwrap(),
+                   "{\"location\":{\"lat\":\"NaN\"}}");
+        assert_eq!(to_json(b"", stop_type).unwrap(), "{}");
+        assert!(to_json(&field_varint(1, 1), stop_type).is_err());
+    }
+}
@@ -1,27 +1,97 @@
 pub mod pbiter;
 pub mod query;
+pub mod stream;
+pub mod json;
 mod descriptors;
 mod compiler;
+mod base64;
 
-pub use descriptors::MessageDescriptor;
+#[cfg(feature = "protobuf-c")]
+pub use descriptors::{MessageDescriptor, FieldDescriptor, EnumDescriptor};
+pub use descriptors::{Type, Label, MessageInfo, FieldInfo, EnumInfo};
+pub use descriptors::{Schemaless, SchemalessField, NoEnum};
+pub use descriptors::{DescriptorPool, PoolMessage, PoolField, PoolEnum,
+                      LoadError};
+pub use compiler::{CompileError, CompileWarning};
 use pbiter::PBMessage;
 use std::ptr::null;
+#[cfg(feature = "protobuf-c")]
 use std::ffi::CStr;
 use std::slice;
 extern crate libc;
+extern crate regex;
 
-pub fn compile(expr: &str, rootmessage: &MessageDescriptor)
-           -> Result<PBExpr, &'static str> {
-    let raw = try!(compiler::parser::parse(expr));
-    compiler::typecheck::typecheck(raw, rootmessage)
+pub struct CompileOptions {
+    // Whether has() is true for a scalar field that is present on the
+    // wire but holds its zero value (0, empty string). proto3 does not
+    // serialize defaults, so a zero on the wire usually means a proto2
+    // field or an explicitly `optional` proto3 field was set.
+    pub zero_is_present: bool,
 }
-pub use query::{query, query_stream};
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions { zero_is_present: true }
+    }
+}
+
+pub fn compile<'d, M>(expr: &str, rootmessage: M)
+                      -> Result<PBExpr, CompileError>
+    where M: MessageInfo<'d>
+{
+    compile_with_options(expr, rootmessage, &CompileOptions::default())
+}
+
+pub fn compile_with_options<'d, M>(expr: &str, rootmessage: M,
+                                   opts: &CompileOptions)
+                                   -> Result<PBExpr, CompileError>
+    where M: MessageInfo<'d>
+{
+    compile_with_warnings(expr, rootmessage, opts).map(|(expr, _)| expr)
+}
+
+// Also returns warnings about parts of the query that can never match.
+pub fn compile_with_warnings<'d, M>(expr: &str, rootmessage: M,
+                                    opts: &CompileOptions)
+                                    -> Result<(PBExpr, Vec<CompileWarning>),
+                                              CompileError>
+    where M: MessageInfo<'d>
+{
+    let raw = try!(compiler::parser::parse(expr).map_err(
+        |e| CompileError::from_parse(expr, e)));
+    let mut cx = compiler::typecheck::Checker::new(opts);
+    let compiled = try!(compiler::typecheck::typecheck(raw, rootmessage,
+                                                       &mut cx).map_err(
+        |e| CompileError::from_typecheck(expr, e)));
+    let warnings = cx.warnings.into_iter().map(
+        |w| CompileError::from_typecheck(expr, w)).collect();
+    Ok((compiled, warnings))
+}
+
+/// Looks up a oneof of message by name, to find which member is set in
+/// messages of that type.
+pub fn compile_oneof<'d, M>(oneof: &str, message: M)
+                            -> Result<Oneof, CompileError>
+    where M: MessageInfo<'d>
+{
+    let members = try!(compiler::typecheck::oneof_members(oneof, message)
+        .map_err(|e| CompileError::from_typecheck(oneof, e)));
+    Ok(Oneof { names: members.iter().map(|f| f.name().to_string()).collect(),
+               cases: members.iter().map(|f| f.number()).collect() })
+}
+pub use query::{query, PBValue, Oneof};
+pub use json::to_json;
+pub use stream::{query_stream, StreamError, RecordReader, DelimitedReader,
+                 BigEndianReader, TFRecordReader, Base64LineReader};
+pub use pbiter::DecodeError;
 use query::PBExpr;
 
+#[cfg(feature = "protobuf-c")]
 #[no_mangle]
-pub unsafe fn pbquery_compile(cexpr: *const libc::c_char,
-                              prootmessage: *const MessageDescriptor)
-                              -> *const PBExpr {
+pub unsafe extern "C" fn pbquery_compile(
+    cexpr: *const libc::c_char, prootmessage: *const MessageDescriptor)
+    -> *const PBExpr
+{
     let expr = match CStr::from_ptr(cexpr).to_str() {
         Ok(s) => s,
         Err(_) => return null(),
@@ -32,11 +102,19 @@ pub unsafe fn pbquery_compile(cexpr: *const libc::c_char,
     };
 
     match compile(expr, rootmessage) {
-        Ok(r) => &r,
+        Ok(r) => Box::into_raw(Box::new(r)),
         Err(_) => null(),
     }
 }
 
+// Frees an expression returned by pbquery_compile.
+#[no_mangle]
+pub unsafe extern "C" fn pbquery_free(cexpr: *const PBExpr) {
+    if !cexpr.is_null() {
+        drop(Box::from_raw(cexpr as *mut PBExpr));
+    }
+}
+
 #[repr(C)]
 pub struct C_PBMessage {
     buf: *const u8,
@@ -46,12 +124,14 @@ pub struct C_PBMessage {
 }
 pub type CCallback = extern fn(msg: *const C_PBMessage,
                                cbdata: *const libc::c_void) -> bool;
+// Returns 0 on success and -1 if the message could not be decoded.
 #[no_mangle]
-pub unsafe fn pbquery_run(cexpr: *const PBExpr, buf: *const u8, len: usize,
-                          callback: CCallback, cbdata: *mut libc::c_void)
-                          -> () {
+pub unsafe extern "C" fn pbquery_run(
+    cexpr: *const PBExpr, buf: *const u8, len: usize, callback: CCallback,
+    cbdata: *mut libc::c_void) -> libc::c_int
+{
     let expr = match cexpr.as_ref() {
-        None => return,
+        None => return -1,
         Some(r) => r,
     };
     let msg = slice::from_raw_parts(buf, len);
@@ -61,12 +141,127 @@ pub unsafe fn pbquery_run(cexpr: *const PBExpr, buf: *const u8, len: usize,
         tag: message.tag,
         wiretype: message.wiretype },
                                 cbdata);
-    query::query(msg, expr, &mut cb);
+    match query::query(msg, expr, &mut cb) {
+        Ok(_) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum C_PBValueKind {
+    INT64, UINT64, FLOAT, DOUBLE, BOOL, STRING, BYTES, ENUM, MESSAGE,
+}
+
+// A decoded value. Integers and bools are in int_value (u64s in
+// uint_value), floats in float_value. buf and len hold the contents of a
+// string, bytes or message, or the name of an enum value, which is not
+// NUL-terminated and is null if the number has no name.
+#[repr(C)]
+pub struct C_PBValue {
+    kind: C_PBValueKind,
+    tag: u32,
+    int_value: i64,
+    uint_value: u64,
+    float_value: f64,
+    buf: *const u8,
+    len: usize,
+}
+
+impl C_PBValue {
+    fn new(tag: u32, value: PBValue) -> C_PBValue {
+        let v = C_PBValue { kind: C_PBValueKind::INT64, tag: tag,
+                            int_value: 0, uint_value: 0, float_value: 0.0,
+                            buf: null(), len: 0 };
+        match value {
+            PBValue::I64(i) => C_PBValue { int_value: i, ..v },
+            PBValue::U64(u) =>
+                C_PBValue { kind: C_PBValueKind::UINT64, uint_value: u, ..v },
+            PBValue::F32(f) => C_PBValue { kind: C_PBValueKind::FLOAT,
+                                           float_value: f as f64, ..v },
+            PBValue::F64(f) =>
+                C_PBValue { kind: C_PBValueKind::DOUBLE, float_value: f, ..v },
+            PBValue::Bool(b) => C_PBValue { kind: C_PBValueKind::BOOL,
+                                            int_value: b as i64, ..v },
+            PBValue::Str(s) => C_PBValue { kind: C_PBValueKind::STRING,
+                                           buf: s.as_ptr(), len: s.len(), ..v },
+            PBValue::Bytes(b) => C_PBValue { kind: C_PBValueKind::BYTES,
+                                             buf: b.as_ptr(), len: b.len(), ..v },
+            PBValue::Enum { number, name } => C_PBValue {
+                kind: C_PBValueKind::ENUM, int_value: number as i64,
+                buf: name.map_or(null(), str::as_ptr),
+                len: name.map_or(0, str::len), ..v },
+            PBValue::Message(b) => C_PBValue { kind: C_PBValueKind::MESSAGE,
+                                               buf: b.as_ptr(), len: b.len(),
+                                               ..v },
+        }
+    }
+}
+
+pub type CValueCallback = extern fn(value: *const C_PBValue,
+                                    cbdata: *const libc::c_void) -> bool;
+
+// Like pbquery_run, but passes each match decoded according to its type.
+// Returns 0 on success and -1 if a message or value could not be decoded.
+#[no_mangle]
+pub unsafe extern "C" fn pbquery_run_values(
+    cexpr: *const PBExpr, buf: *const u8, len: usize, callback: CValueCallback,
+    cbdata: *mut libc::c_void) -> libc::c_int
+{
+    let expr = match cexpr.as_ref() {
+        None => return -1,
+        Some(r) => r,
+    };
+    let msg = slice::from_raw_parts(buf, len);
+    for m in expr.matches(msg) {
+        let value = match m.and_then(|m| expr.value(&m).map(|v| (m.tag, v))) {
+            Ok((tag, v)) => C_PBValue::new(tag, v),
+            Err(_) => return -1,
+        };
+        if !callback(&value, cbdata) { break }
+    }
+    0
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use descriptors::native::tests::{transit_pool, stop, feed_with};
+
+    type Collected = Vec<(C_PBValueKind, i64, Vec<u8>)>;
+
+    extern fn collect_value(value: *const C_PBValue,
+                            cbdata: *const libc::c_void) -> bool {
+        let out = unsafe { &mut *(cbdata as *mut Collected) };
+        let v = unsafe { &*value };
+        let buf = if v.buf.is_null() { vec!() } else {
+            unsafe { slice::from_raw_parts(v.buf, v.len) }.to_vec()
+        };
+        out.push((v.kind, v.int_value, buf));
+        true
+    }
+
     #[test]
-    fn it_works() {
+    fn test_run_values() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let buf = feed_with(&[stop("a", &[(4, 0)]), stop("b", &[(4, 3)])]);
+
+        let expr = compile("stops.kind", feed).unwrap();
+        let mut out: Collected = vec!();
+        let r = unsafe {
+            pbquery_run_values(&expr, buf.as_ptr(), buf.len(), collect_value,
+                               &mut out as *mut _ as *mut libc::c_void)
+        };
+        assert_eq!(r, 0);
+        assert_eq!(out, vec!((C_PBValueKind::ENUM, 0, b"BUS".to_vec()),
+                             (C_PBValueKind::ENUM, 3, vec!())));
+
+        let r = unsafe {
+            pbquery_run_values(&expr, buf.as_ptr(), buf.len() - 1,
+                               collect_value,
+                               &mut out as *mut _ as *mut libc::c_void)
+        };
+        assert_eq!(r, -1);
     }
 }
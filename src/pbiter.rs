@@ -1,31 +1,85 @@
 use std::io::prelude::*;
+use std::fmt;
+use std::error::Error;
+use ::descriptors::Type;
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    // The buffer ends in the middle of a tag or value.
+    Truncated,
+    // A varint longer than the 10 bytes needed for 64 bits.
+    OverlongVarint,
+    BadWireType(u8),
+    // A length prefix running past the end of the buffer.
+    LengthOverflow,
+    // A value read as a different wire type than it was encoded with.
+    WireTypeMismatch(WireType),
+    InvalidUtf8,
+    // An END_GROUP tag that doesn't close a group of the same field.
+    UnmatchedEndGroup,
+    // Groups nested more than MAX_DEPTH deep.
+    TooDeep,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DecodeError::Truncated => write!(f, "truncated message"),
+            &DecodeError::OverlongVarint => write!(f, "varint too long"),
+            &DecodeError::BadWireType(t) => write!(f, "bad wire type {}", t),
+            &DecodeError::LengthOverflow =>
+                write!(f, "length prefix past end of message"),
+            &DecodeError::WireTypeMismatch(t) =>
+                write!(f, "unexpected wire type {:?}", t),
+            &DecodeError::InvalidUtf8 => write!(f, "invalid UTF-8 in string"),
+            &DecodeError::UnmatchedEndGroup => write!(f, "unmatched end of group"),
+            &DecodeError::TooDeep => write!(f, "groups nested too deeply"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
 
-fn read_varint(buf: &[u8]) -> (usize, usize) {
-    let mut acc = 0 as usize;
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+// How deeply groups can nest, as in protobuf's own parsers. Finding a
+// group's end means reading every group inside it, so without a limit a
+// run of START_GROUP tags would overflow the stack.
+pub(crate) const MAX_DEPTH: usize = 100;
+
+fn read_varint(buf: &[u8]) -> DecodeResult<(u64, usize)> {
+    let mut acc = 0 as u64;
     let mut cnt = 0 as usize;
     for b in buf {
-        acc += ((b & 0x7f) as usize) << (cnt * 7);
+        if cnt == MAX_VARINT_LEN { return Err(DecodeError::OverlongVarint) }
+        acc |= ((b & 0x7f) as u64).wrapping_shl(cnt as u32 * 7);
         cnt += 1;
-        if b & 0x80 == 0 { return (acc, cnt) }
+        if b & 0x80 == 0 { return Ok((acc, cnt)) }
     }
-    panic!("Ill formed varint");
+    Err(DecodeError::Truncated)
 }
 
+// A group is read as a single START_GROUP record whose contents are the
+// fields up to its END_GROUP tag.
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
-pub enum WireType { VARINT = 0, FIXED64 = 1, LENGTH_PREFIXED = 2, FIXED32 = 5 }
-fn wire_type(tagbits: u8) -> WireType {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireType { VARINT = 0, FIXED64 = 1, LENGTH_PREFIXED = 2,
+                    START_GROUP = 3, END_GROUP = 4, FIXED32 = 5 }
+fn wire_type(tagbits: u8) -> DecodeResult<WireType> {
     match tagbits {
-        0 => WireType::VARINT,
-        1 => WireType::FIXED64,
-        2 => WireType::LENGTH_PREFIXED,
-        5 => WireType::FIXED32,
-        _ => panic!("Bad wiretype {}", tagbits),
+        0 => Ok(WireType::VARINT),
+        1 => Ok(WireType::FIXED64),
+        2 => Ok(WireType::LENGTH_PREFIXED),
+        3 => Ok(WireType::START_GROUP),
+        4 => Ok(WireType::END_GROUP),
+        5 => Ok(WireType::FIXED32),
+        _ => Err(DecodeError::BadWireType(tagbits)),
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PBMessage<'a> {
     pub contents: &'a [u8],
     pub tag: u32,
@@ -34,29 +88,121 @@ pub struct PBMessage<'a> {
 }
 
 impl<'a> PBMessage<'a> {
-    pub fn as_int(&self) -> i32 {
+    fn fixed32(&self) -> DecodeResult<u32> {
+        if self.contents.len() != 4 { return Err(DecodeError::Truncated) }
+        let mut b = [0; 4];
+        b.copy_from_slice(self.contents);
+        Ok(u32::from_le_bytes(b))
+    }
+    fn fixed64(&self) -> DecodeResult<u64> {
+        if self.contents.len() != 8 { return Err(DecodeError::Truncated) }
+        let mut b = [0; 8];
+        b.copy_from_slice(self.contents);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    // The raw bits of a VARINT, FIXED32 or FIXED64 value.
+    fn raw_bits(&self) -> DecodeResult<u64> {
         match self.wiretype {
-            WireType::VARINT => read_varint(self.contents).0 as i32,
-            _ => unimplemented!()
+            WireType::VARINT => read_varint(self.contents).map(|(v, _)| v),
+            WireType::FIXED32 => self.fixed32().map(|v| v as u64),
+            WireType::FIXED64 => self.fixed64(),
+            t => Err(DecodeError::WireTypeMismatch(t)),
         }
     }
-    pub fn as_float(&self) -> f64 {
+
+    // Decode an integer field of type t. UINT64 and FIXED64 values above
+    // i64::MAX wrap around; use as_uint for those.
+    pub fn as_int(&self, t: Type) -> DecodeResult<i64> {
+        let v = try!(self.raw_bits());
+        Ok(match t {
+            Type::SINT32 | Type::SINT64 => ((v >> 1) as i64) ^ -((v & 1) as i64),
+            // negative int32s are sign extended to 64 bits on the wire
+            Type::INT32 | Type::SFIXED32 | Type::ENUM => v as i32 as i64,
+            Type::UINT32 | Type::FIXED32 => v as u32 as i64,
+            Type::BOOL => (v != 0) as i64,
+            _ => v as i64,
+        })
+    }
+    pub fn as_uint(&self) -> DecodeResult<u64> {
+        self.raw_bits()
+    }
+    pub fn as_float(&self) -> DecodeResult<f64> {
         match self.wiretype {
-            WireType::FIXED32 => {
-                assert!(self.contents.len() == 4);
-                let p = self.contents.as_ptr() as *const f32;
-                unsafe { *p as f64 }
-            },
-            WireType::FIXED64 => {
-                assert!(self.contents.len() == 8);
-                let p = self.contents.as_ptr() as *const f64;
-                unsafe { *p }
-            },
-            _ => panic!("Not a float"),
+            WireType::FIXED32 => self.fixed32().map(|v| f32::from_bits(v) as f64),
+            WireType::FIXED64 => self.fixed64().map(f64::from_bits),
+            t => Err(DecodeError::WireTypeMismatch(t)),
         }
     }
-    pub fn as_str(&self) -> &str {
-        ::std::str::from_utf8(self.contents).unwrap()
+    // Whether this is the zero value for its wire type: 0, 0.0,
+    // or empty.
+    pub fn is_zero(&self) -> DecodeResult<bool> {
+        match self.wiretype {
+            WireType::VARINT => self.raw_bits().map(|v| v == 0),
+            WireType::FIXED32 | WireType::FIXED64 =>
+                Ok(self.contents.iter().all(|&b| b == 0)),
+            // a string of NULs is still a string
+            _ => Ok(self.contents.is_empty()),
+        }
+    }
+    pub fn as_str(&self) -> DecodeResult<&'a str> {
+        ::std::str::from_utf8(self.contents).or(Err(DecodeError::InvalidUtf8))
+    }
+    // Whether this record can hold fields: a length-delimited value
+    // that may be a message, or a group.
+    pub fn is_nested(&self) -> bool {
+        self.wiretype == WireType::LENGTH_PREFIXED ||
+            self.wiretype == WireType::START_GROUP
+    }
+
+    // The elements of a packed repeated field, each encoded as wiretype.
+    pub fn unpack(&self, wiretype: WireType) -> PackedIter<'a> {
+        PackedIter { buf: self.contents, tag: self.tag, wiretype: wiretype }
+    }
+}
+
+// The wire type of each element of a packed repeated field of type t,
+// or None if t can't be packed.
+pub fn packed_wire_type(t: Type) -> Option<WireType> {
+    match t {
+        Type::FIXED32 | Type::SFIXED32 | Type::FLOAT => Some(WireType::FIXED32),
+        Type::FIXED64 | Type::SFIXED64 | Type::DOUBLE => Some(WireType::FIXED64),
+        Type::STRING | Type::BYTES | Type::MESSAGE | Type::UNKNOWN => None,
+        _ => Some(WireType::VARINT),
+    }
+}
+
+// Elements have their field's tag, and no tag bytes of their own.
+pub struct PackedIter<'a> {
+    buf: &'a [u8],
+    tag: u32,
+    wiretype: WireType,
+}
+
+impl<'a> PackedIter<'a> {
+    fn next_element(&mut self) -> DecodeResult<PBMessage<'a>> {
+        let len = match self.wiretype {
+            WireType::VARINT => try!(read_varint(self.buf)).1,
+            WireType::FIXED32 => 4,
+            WireType::FIXED64 => 8,
+            t => return Err(DecodeError::WireTypeMismatch(t)),
+        };
+        if self.buf.len() < len { return Err(DecodeError::Truncated) }
+        let (element, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(PBMessage { contents: element, tag: self.tag,
+                       wiretype: self.wiretype, bytes: element })
+    }
+}
+
+impl<'a> Iterator for PackedIter<'a> {
+    type Item = DecodeResult<PBMessage<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<PBMessage<'a>>> {
+        if self.buf.is_empty() { return None }
+        let r = self.next_element();
+        if r.is_err() { self.buf = &[] }
+        Some(r)
     }
 }
     
@@ -69,43 +215,213 @@ impl<'a> PBIter<'a> {
         PBIter { buf: buf }
     }
     pub fn len(&self) -> usize { self.buf.len() }
-}
 
-    
-impl<'a> Iterator for PBIter<'a> {
-    type Item = PBMessage<'a>;
+    fn next_message(&mut self) -> DecodeResult<PBMessage<'a>> {
+        self.next_message_at(0)
+    }
 
-    fn next(&mut self) -> Option<PBMessage<'a>> {
-        if self.buf.is_empty() { return None }
-        let (rawtag, taglen) = read_varint(self.buf);
-        let wiretype = wire_type((rawtag & 0x7) as u8);
+    // The next record, inside depth enclosing groups.
+    fn next_message_at(&mut self, depth: usize) -> DecodeResult<PBMessage<'a>> {
+        let (rawtag, taglen) = try!(read_varint(self.buf));
+        let wiretype = try!(wire_type((rawtag & 0x7) as u8));
+        let tag = (rawtag >> 3) as u32;
         let rest = self.buf.split_at(taglen).1;
-        if rest.is_empty() { return None }
-        let (len, start) = match wiretype {
-            WireType::FIXED64 => (8, 0),
-            WireType::FIXED32 => (4, 0),
-            WireType::LENGTH_PREFIXED => read_varint(rest),
-            WireType::VARINT => (read_varint(rest).1, 0),
+        // contents are rest[start..start + len], followed by end more
+        // bytes of the record
+        let (len, start, end) = match wiretype {
+            WireType::FIXED64 => (8, 0, 0),
+            WireType::FIXED32 => (4, 0, 0),
+            WireType::LENGTH_PREFIXED => {
+                let (len, start) = try!(read_varint(rest));
+                if len > (rest.len() - start) as u64 {
+                    return Err(DecodeError::LengthOverflow)
+                }
+                (len as usize, start, 0)
+            },
+            WireType::VARINT => (try!(read_varint(rest)).1, 0, 0),
+            WireType::START_GROUP => {
+                if depth == MAX_DEPTH { return Err(DecodeError::TooDeep) }
+                let (len, end) = try!(group_len(rest, tag, depth + 1));
+                (len, 0, end)
+            },
+            WireType::END_GROUP => (0, 0, 0),
         };
-        if rest.len() < start + len { return None }
-        let splits = rest[start..].split_at(len);
+        if rest.len() < start + len + end { return Err(DecodeError::Truncated) }
+        let contents = &rest[start..start + len];
         let origbuf = self.buf;
-        self.buf = splits.1;
-        let msgsize = taglen + start + len;
-
-//        println!("Msg wiretype {:?}, tag {:?}, contentlen {}, totallen {}",
-//                 wiretype, rawtag >> 3, splits.0.len(), msgsize);
-        return Some(PBMessage { contents: splits.0,
-                                tag: (rawtag >> 3) as u32,
-                                wiretype: wiretype,
-                                bytes: &origbuf[0..msgsize],
+        self.buf = &rest[start + len + end..];
+        let msgsize = taglen + start + len + end;
+
+        Ok(PBMessage { contents: contents,
+                       tag: tag,
+                       wiretype: wiretype,
+                       bytes: &origbuf[0..msgsize],
+        })
+    }
+}
+
+// The length of the contents of a group of field tag, which run up to
+// its END_GROUP tag, and the length of that tag. The group is inside
+// depth groups, counting itself.
+fn group_len(buf: &[u8], tag: u32, depth: usize)
+             -> DecodeResult<(usize, usize)> {
+    let mut fields = PBIter::new(buf);
+    loop {
+        if fields.buf.is_empty() { return Err(DecodeError::Truncated) }
+        let m = try!(fields.next_message_at(depth));
+        if m.wiretype == WireType::END_GROUP {
+            if m.tag != tag { return Err(DecodeError::UnmatchedEndGroup) }
+            let endtag = m.bytes.len();
+            return Ok((buf.len() - fields.buf.len() - endtag, endtag));
+        }
+    }
+}
+
+impl<'a> Iterator for PBIter<'a> {
+    type Item = DecodeResult<PBMessage<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<PBMessage<'a>>> {
+        if self.buf.is_empty() { return None }
+        let r = self.next_message().and_then(|m| {
+            if m.wiretype == WireType::END_GROUP {
+                Err(DecodeError::UnmatchedEndGroup)
+            } else {
+                Ok(m)
+            }
         });
+        // nothing after a decode error can be trusted
+        if r.is_err() { self.buf = &[] }
+        Some(r)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn field(wiretype: WireType, contents: &[u8]) -> PBMessage {
+        PBMessage { contents: contents, tag: 1, wiretype: wiretype,
+                    bytes: contents }
+    }
+
     #[test]
-    fn it_works() {
+    fn test_as_int() {
+        let minus_one = [0xff, 0xff, 0xff, 0xff, 0xff,
+                         0xff, 0xff, 0xff, 0xff, 0x01];
+        let m = field(WireType::VARINT, &minus_one);
+        assert_eq!(m.as_int(Type::INT32).unwrap(), -1);
+        assert_eq!(m.as_int(Type::INT64).unwrap(), -1);
+        assert_eq!(m.as_uint().unwrap(), u64::max_value());
+
+        let ts = [0x80, 0xd0, 0x95, 0xff, 0xbc, 0x31]; // 1700000000000
+        let m = field(WireType::VARINT, &ts);
+        assert_eq!(m.as_int(Type::INT64).unwrap(), 1700000000000);
+        assert_eq!(m.as_int(Type::UINT64).unwrap(), 1700000000000);
+
+        let m = field(WireType::VARINT, &[0x03]);
+        assert_eq!(m.as_int(Type::SINT32).unwrap(), -2);
+        assert_eq!(m.as_int(Type::SINT64).unwrap(), -2);
+        let m = field(WireType::VARINT, &[0x04]);
+        assert_eq!(m.as_int(Type::SINT32).unwrap(), 2);
+        let m = field(WireType::VARINT, &[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(m.as_int(Type::SINT32).unwrap(), i32::min_value() as i64);
+        assert_eq!(m.as_int(Type::UINT32).unwrap(), u32::max_value() as i64);
+
+        let m = field(WireType::FIXED32, &[0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(m.as_int(Type::SFIXED32).unwrap(), -2);
+        assert_eq!(m.as_int(Type::FIXED32).unwrap(), 0xfffffffe);
+        let m = field(WireType::FIXED64, &[0xfe, 0xff, 0xff, 0xff,
+                                           0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(m.as_int(Type::SFIXED64).unwrap(), -2);
+        assert_eq!(m.as_uint().unwrap(), u64::max_value() - 1);
+    }
+
+    #[test]
+    fn test_unpack() {
+        // 3, 270, -1 as packed varints
+        let m = field(WireType::LENGTH_PREFIXED,
+                      b"\x03\x8e\x02\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01");
+        let ints: Vec<_> = m.unpack(WireType::VARINT)
+            .map(|e| e.unwrap().as_int(Type::INT32).unwrap()).collect();
+        assert_eq!(ints, vec![3, 270, -1]);
+
+        let m = field(WireType::LENGTH_PREFIXED, b"\x00\x00\xc0\x3f\x00\x00\x20\x40");
+        let floats: Vec<_> = m.unpack(WireType::FIXED32)
+            .map(|e| e.unwrap().as_float().unwrap()).collect();
+        assert_eq!(floats, vec![1.5, 2.5]);
+        assert_eq!(packed_wire_type(Type::DOUBLE), Some(WireType::FIXED64));
+        assert_eq!(packed_wire_type(Type::BOOL), Some(WireType::VARINT));
+        assert_eq!(packed_wire_type(Type::STRING), None);
+    }
+
+    #[test]
+    fn test_groups() {
+        // group 1 {1: 1, group 2 {}}, then 2: 2
+        let buf = b"\x0b\x08\x01\x13\x14\x0c\x10\x02";
+        let fields: Vec<_> = PBIter::new(buf).map(|r| r.unwrap()).collect();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].tag, 1);
+        assert_eq!(fields[0].wiretype, WireType::START_GROUP);
+        assert_eq!(fields[0].contents, b"\x08\x01\x13\x14");
+        assert_eq!(fields[0].bytes, b"\x0b\x08\x01\x13\x14\x0c");
+        assert!(fields[0].is_nested());
+        let inner: Vec<_> = PBIter::new(fields[0].contents)
+            .map(|r| r.unwrap().tag).collect();
+        assert_eq!(inner, vec![1, 2]);
+        assert_eq!(fields[1].as_int(Type::INT32).unwrap(), 2);
+
+        assert_eq!(decode(b"\x0b\x08\x01"), vec![Err(DecodeError::Truncated)]);
+        assert_eq!(decode(b"\x0b\x14\x0c"),
+                   vec![Err(DecodeError::UnmatchedEndGroup)]);
+        assert_eq!(decode(b"\x08\x01\x0c"),
+                   vec![Ok(1), Err(DecodeError::UnmatchedEndGroup)]);
+    }
+
+    fn nested_groups(depth: usize) -> Vec<u8> {
+        let mut buf = vec![0x0b; depth];
+        buf.extend(vec![0x0c; depth]);
+        buf
+    }
+
+    #[test]
+    fn test_group_depth() {
+        assert_eq!(decode(&nested_groups(MAX_DEPTH)), vec![Ok(1)]);
+        assert_eq!(decode(&nested_groups(MAX_DEPTH + 1)),
+                   vec![Err(DecodeError::TooDeep)]);
+        // far deeper than the stack would allow without the limit
+        assert_eq!(decode(&vec![0x0b; 1 << 20]),
+                   vec![Err(DecodeError::TooDeep)]);
+    }
+
+    fn decode(buf: &[u8]) -> Vec<DecodeResult<u32>> {
+        PBIter::new(buf).map(|r| r.map(|m| m.tag)).collect()
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"\x08\x01\x12\x01A"), vec![Ok(1), Ok(2)]);
+        assert_eq!(decode(b"\x08\x01\x08"),
+                   vec![Ok(1), Err(DecodeError::Truncated)]);
+        assert_eq!(decode(b"\x08\x81"), vec![Err(DecodeError::Truncated)]);
+        assert_eq!(decode(b"\x08\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+                   vec![Err(DecodeError::OverlongVarint)]);
+        assert_eq!(decode(b"\x0e"), vec![Err(DecodeError::BadWireType(6))]);
+        assert_eq!(decode(b"\x0f"), vec![Err(DecodeError::BadWireType(7))]);
+        assert_eq!(decode(b"\x12\x05AB"), vec![Err(DecodeError::LengthOverflow)]);
+        assert_eq!(decode(b"\x12\xff\xff\xff\xff\xff\xff\xff\xff\x7f"),
+                   vec![Err(DecodeError::LengthOverflow)]);
+        assert_eq!(decode(b"\x0d\x00\x00"), vec![Err(DecodeError::Truncated)]);
+
+        let m = field(WireType::LENGTH_PREFIXED, b"\x01\x00\x00");
+        let r: Vec<_> = m.unpack(WireType::FIXED32).collect();
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].err(), Some(DecodeError::Truncated));
+
+        let m = field(WireType::LENGTH_PREFIXED, b"\xff");
+        assert_eq!(m.as_str(), Err(DecodeError::InvalidUtf8));
+        assert_eq!(m.as_int(Type::INT32),
+                   Err(DecodeError::WireTypeMismatch(WireType::LENGTH_PREFIXED)));
+        assert_eq!(m.as_float(),
+                   Err(DecodeError::WireTypeMismatch(WireType::LENGTH_PREFIXED)));
     }
 }
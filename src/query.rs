@@ -1,56 +1,341 @@
 use pbiter::*;
 use ::descriptors::{Type};
 use std::collections::HashSet;
+use std::cmp::Ordering;
+use regex::bytes::Regex;
 
 #[derive(Debug)]
 pub enum PBItem {
-    Int(i32),
+    Int(i64),
+    // an integer literal above i64::MAX
+    Uint(u64),
     Float(f64),
     Str(String),
+    Bool(bool),
+}
+
+// What a filter tests: the filtered field itself, or a path within it.
+#[derive(Debug)]
+pub enum PBPath {
     At,
     Path(PBExpr),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp { Eq, Ne, Lt, Le, Gt, Ge }
+
+impl CmpOp {
+    // The same comparison with the operands swapped.
+    pub fn flip(self) -> CmpOp {
+        match self {
+            CmpOp::Lt => CmpOp::Gt,
+            CmpOp::Le => CmpOp::Ge,
+            CmpOp::Gt => CmpOp::Lt,
+            CmpOp::Ge => CmpOp::Le,
+            op => op,
+        }
+    }
+
+    pub fn test(self, ord: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ord == Ordering::Equal,
+            CmpOp::Ne => ord != Ordering::Equal,
+            CmpOp::Lt => ord == Ordering::Less,
+            CmpOp::Le => ord != Ordering::Greater,
+            CmpOp::Gt => ord == Ordering::Greater,
+            CmpOp::Ge => ord != Ordering::Less,
+        }
+    }
+}
+
+// A filter on a field is true if some occurrence of the field satisfies
+// it, so for a missing field = and != are both false (an unset bool reads
+// as its default, false). which() treats an unset oneof the same way. Not
+// is the only way to match a missing field.
 #[derive(Debug)]
 pub enum PBFilter {
-    EqFilter { atom: PBItem, path: PBItem, invert: bool },
-    InStrFilter(PBItem, HashSet<String>),
-    InIntFilter(PBItem, HashSet<i32>),
-    IdxFilter(u32),
+    EqFilter { atom: PBItem, path: PBPath, pathtype: Type, invert: bool },
+    RxFilter { rx: Regex, path: PBPath, invert: bool },
+    InStrFilter(PBPath, HashSet<String>),
+    // i128 holds both int64 and uint64 values
+    InIntFilter(PBPath, Type, HashSet<i128>),
+    CmpFilter { atom: PBItem, path: PBPath, pathtype: Type, op: CmpOp },
+    // Existential: true if any pair of lhs and rhs occurrences compares
+    // true, and false if either side is missing.
+    PathCmpFilter { lhs: PBPath, lhstype: Type,
+                    rhs: PBPath, rhstype: Type, op: CmpOp },
+    HasFilter { path: PBPath, pathtype: Type, zero_is_present: bool,
+                invert: bool },
+    // Whether the member of a oneof that is set, out of the field numbers
+    // in cases, is one of those in active.
+    WhichFilter { cases: Vec<u32>, active: HashSet<u32>, invert: bool },
+    IdxFilter(i32),
+    SliceFilter(Option<i32>, Option<i32>),
+    And(Box<PBFilter>, Box<PBFilter>),
+    Or(Box<PBFilter>, Box<PBFilter>),
+    Not(Box<PBFilter>),
     TrueFilter,
+    FalseFilter,
 }
 
-fn eval_path<'a>(path: &PBItem, msg: &PBMessage<'a>) -> Option<PBMessage<'a>> {
-    let mut ret = None;
+// True if any occurrence of path within msg satisfies pred, so that
+// filters on repeated fields match when at least one element does.
+fn any_path<'a, P>(path: &PBPath, msg: &PBMessage<'a>, mut pred: P)
+                   -> DecodeResult<bool>
+    where P: FnMut(&PBMessage<'a>) -> DecodeResult<bool>
+{
     match path {
-        &PBItem::At => Some(*msg),
-        &PBItem::Path(ref p) => {
-            query(msg.contents, p, &mut |m| { ret = Some(m); false});
-            ret
+        &PBPath::At => pred(msg),
+        &PBPath::Path(ref p) => {
+            for m in p.matches(msg.contents) {
+                if try!(pred(&try!(m))) { return Ok(true) }
+            }
+            Ok(false)
         }
-        _ => panic!("Not a path!")
     }
 }
 
-            
+// The value of an integer field, or None if it is a UINT64 or FIXED64
+// too big for an i64 (and so bigger than any literal).
+fn int_value(msg: &PBMessage, t: Type) -> DecodeResult<Option<i64>> {
+    match t {
+        Type::UINT64 | Type::FIXED64 => {
+            let v = try!(msg.as_uint());
+            Ok(if v > i64::max_value() as u64 { None } else { Some(v as i64) })
+        },
+        _ => msg.as_int(t).map(Some),
+    }
+}
+
+fn cmp_int(msg: &PBMessage, t: Type, i: i64) -> DecodeResult<Ordering> {
+    int_value(msg, t).map(|v| v.map_or(Ordering::Greater, |v| v.cmp(&i)))
+}
+
+// The value of an integer field, or None if it's negative.
+fn uint_value(msg: &PBMessage, t: Type) -> DecodeResult<Option<u64>> {
+    match t {
+        Type::UINT64 | Type::FIXED64 => msg.as_uint().map(Some),
+        _ => msg.as_int(t).map(|v| if v < 0 { None } else { Some(v as u64) }),
+    }
+}
+
+fn cmp_uint(msg: &PBMessage, t: Type, u: u64) -> DecodeResult<Ordering> {
+    uint_value(msg, t).map(|v| v.map_or(Ordering::Less, |v| v.cmp(&u)))
+}
+
+fn as_number(msg: &PBMessage, t: Type) -> DecodeResult<f64> {
+    if t.is_floaty() {
+        msg.as_float()
+    } else {
+        match try!(int_value(msg, t)) {
+            Some(v) => Ok(v as f64),
+            None => msg.as_uint().map(|v| v as f64),
+        }
+    }
+}
+
+// The type to read a value as. Schemaless values go by their wire type,
+// with fixed-width ones read as floats if they're compared with a float.
+fn value_type(msg: &PBMessage, t: Type, floaty: bool) -> Type {
+    if t != Type::UNKNOWN {
+        return t;
+    }
+    match msg.wiretype {
+        WireType::VARINT => Type::INT64,
+        WireType::FIXED32 => if floaty { Type::FLOAT } else { Type::SFIXED32 },
+        WireType::FIXED64 => if floaty { Type::DOUBLE } else { Type::SFIXED64 },
+        WireType::LENGTH_PREFIXED => Type::BYTES,
+        WireType::START_GROUP | WireType::END_GROUP => Type::MESSAGE,
+    }
+}
+
+// Compare two field values. The typechecker has made sure they're both
+// numbers, strings, bools or values of one enum, unless one is
+// schemaless, in which case a number and a string don't compare.
+fn cmp_values(l: &PBMessage, ltype: Type, r: &PBMessage, rtype: Type)
+              -> DecodeResult<Option<Ordering>> {
+    let ltype = value_type(l, ltype, rtype.is_floaty());
+    let rtype = value_type(r, rtype, ltype.is_floaty());
+    if ltype == Type::BOOL && rtype == Type::BOOL {
+        // any nonzero varint is true
+        let (lv, rv) = (try!(l.as_uint()) != 0, try!(r.as_uint()) != 0);
+        Ok(Some(lv.cmp(&rv)))
+    } else if ltype.is_stringy() && rtype.is_stringy() {
+        Ok(Some(l.contents.cmp(r.contents)))
+    } else if ltype.is_stringy() || rtype.is_stringy() ||
+        ltype.is_message() || rtype.is_message()
+    {
+        Ok(None)
+    } else if ltype.is_inty() && rtype.is_inty() {
+        let (lv, rv) = (try!(int_value(l, ltype)), try!(int_value(r, rtype)));
+        Ok(Some(match (lv, rv) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (None, None) => try!(l.as_uint()).cmp(&try!(r.as_uint())),
+        }))
+    } else {
+        let (lv, rv) = (try!(as_number(l, ltype)), try!(as_number(r, rtype)));
+        Ok(lv.partial_cmp(&rv))
+    }
+}
+
+// Whether a field value equals atom.
+fn eq_atom(atom: &PBItem, m: &PBMessage, pathtype: Type) -> DecodeResult<bool> {
+    let floaty = if let &PBItem::Float(_) = atom { true } else { false };
+    let t = value_type(m, pathtype, floaty);
+    Ok(match atom {
+        &PBItem::Int(i) if t.is_inty() => try!(int_value(m, t)) == Some(i),
+        &PBItem::Uint(u) if t.is_inty() => try!(uint_value(m, t)) == Some(u),
+        &PBItem::Float(f) if t.is_inty() || t.is_floaty() =>
+            try!(as_number(m, t)) == f,
+        &PBItem::Str(ref s) if t.is_stringy() => m.contents == s.as_bytes(),
+        // any nonzero varint is true
+        &PBItem::Bool(b) if t == Type::BOOL || t.is_inty() => {
+            let v = try!(int_value(m, t));
+            v.map_or(true, |v| v != 0) == b
+        },
+        // a schemaless value of some other wire type
+        _ => false,
+    })
+}
+
+// The field number of the member of a oneof set in msg, out of the
+// members' numbers in cases. If several are on the wire, the last one wins.
+fn set_case(msg: &[u8], cases: &[u32]) -> DecodeResult<Option<u32>> {
+    let mut set = None;
+    for m in PBIter::new(msg) {
+        let m = try!(m);
+        if cases.contains(&m.tag) { set = Some(m.tag) }
+    }
+    Ok(set)
+}
+
+/// The members of a oneof, for asking which of them a message has set.
+#[derive(Debug)]
+pub struct Oneof {
+    pub names: Vec<String>,
+    // the members' field numbers, in the same order as names
+    pub cases: Vec<u32>,
+}
+
+impl Oneof {
+    /// The name of the member set in msg, or None if none is. If several
+    /// members are on the wire, the last one wins.
+    pub fn which<'o>(&'o self, msg: &[u8]) -> DecodeResult<Option<&'o str>> {
+        let set = try!(set_case(msg, &self.cases));
+        Ok(set.and_then(|tag| self.cases.iter().position(|&c| c == tag))
+              .map(|i| &self.names[i][..]))
+    }
+}
+
 impl PBFilter {
-    fn eval(&self, msg: &PBMessage) -> bool {
+    // Evaluate the filter for the idx'th of count occurrences of a field.
+    fn eval_at(&self, msg: &PBMessage, idx: usize, count: usize)
+               -> DecodeResult<bool> {
+        let resolve = |i: i32| if i < 0 { count as isize + i as isize }
+                               else { i as isize };
         match self {
-            &PBFilter::TrueFilter => { true },
-            &PBFilter::EqFilter { ref atom, ref path, invert } => {
-                let submsg = match eval_path(path, msg) {
-                    None => return false,
-                    Some(m) => m,
-                };
-                let v = match atom {
-                    &PBItem::Int(i) => submsg.as_int() == i,
-                    &PBItem::Float(f) => submsg.as_float() == f,
-                    &PBItem::Str(ref s) => submsg.as_str() == s,
-                    _ => unimplemented!()
-                };
-                if invert { !v } else { v }
+            &PBFilter::TrueFilter => Ok(true),
+            &PBFilter::FalseFilter => Ok(false),
+            &PBFilter::And(ref l, ref r) =>
+                Ok(try!(l.eval_at(msg, idx, count)) &&
+                   try!(r.eval_at(msg, idx, count))),
+            &PBFilter::Or(ref l, ref r) =>
+                Ok(try!(l.eval_at(msg, idx, count)) ||
+                   try!(r.eval_at(msg, idx, count))),
+            &PBFilter::Not(ref f) => f.eval_at(msg, idx, count).map(|v| !v),
+            &PBFilter::IdxFilter(i) => Ok(idx as isize == resolve(i)),
+            &PBFilter::SliceFilter(start, end) =>
+                Ok(idx as isize >= start.map_or(0, &resolve) &&
+                   end.map_or(true, |e| (idx as isize) < resolve(e))),
+            &PBFilter::EqFilter { ref atom, ref path, pathtype, invert } => {
+                let mut present = false;
+                let v = try!(any_path(path, msg, |m| {
+                    present = true;
+                    eq_atom(atom, m, pathtype).map(|v| v != invert)
+                }));
+                Ok(match atom {
+                    // an unset bool reads as its default, false
+                    &PBItem::Bool(b) if !present => !b != invert,
+                    _ => v,
+                })
             },
-            _ => unimplemented!()
+            &PBFilter::RxFilter { ref rx, ref path, invert } =>
+                any_path(path, msg, |m| {
+                    let v = m.wiretype == WireType::LENGTH_PREFIXED &&
+                        rx.is_match(m.contents);
+                    Ok(v != invert)
+                }),
+            &PBFilter::InIntFilter(ref path, pathtype, ref set) =>
+                any_path(path, msg, |m| {
+                    let t = value_type(m, pathtype, false);
+                    if !t.is_inty() { return Ok(false) }
+                    let v = match t {
+                        Type::UINT64 | Type::FIXED64 =>
+                            try!(m.as_uint()) as i128,
+                        _ => try!(m.as_int(t)) as i128,
+                    };
+                    Ok(set.contains(&v))
+                }),
+            // a string that isn't valid UTF-8 can't be in the list
+            &PBFilter::InStrFilter(ref path, ref set) =>
+                any_path(path, msg, |m| Ok(
+                    m.wiretype == WireType::LENGTH_PREFIXED &&
+                    m.as_str().map_or(false, |s| set.contains(s)))),
+            &PBFilter::PathCmpFilter { ref lhs, lhstype, ref rhs, rhstype,
+                                       op } =>
+                any_path(lhs, msg, |l| any_path(rhs, msg, |r| {
+                    cmp_values(l, lhstype, r, rhstype)
+                        .map(|o| o.map_or(false, |o| op.test(o)))
+                })),
+            &PBFilter::HasFilter { ref path, pathtype, zero_is_present,
+                                   invert } => {
+                // sub-messages have explicit presence, even when empty
+                let v = try!(any_path(path, msg, |m| {
+                    if zero_is_present || pathtype.is_message() {
+                        Ok(true)
+                    } else {
+                        m.is_zero().map(|z| !z)
+                    }
+                }));
+                Ok(if invert { !v } else { v })
+            },
+            &PBFilter::WhichFilter { ref cases, ref active, invert } => {
+                let set = try!(set_case(msg.contents, cases));
+                Ok(set.map_or(false, |tag| active.contains(&tag) != invert))
+            },
+            &PBFilter::CmpFilter { ref atom, ref path, pathtype, op } =>
+                any_path(path, msg, |m| {
+                    let floaty = if let &PBItem::Float(_) = atom { true }
+                                 else { false };
+                    let t = value_type(m, pathtype, floaty);
+                    let ord = match atom {
+                        &PBItem::Int(i) if t.is_inty() =>
+                            Some(try!(cmp_int(m, t, i))),
+                        &PBItem::Uint(u) if t.is_inty() =>
+                            Some(try!(cmp_uint(m, t, u))),
+                        &PBItem::Float(f) if t.is_inty() || t.is_floaty() =>
+                            try!(as_number(m, t)).partial_cmp(&f),
+                        // a schemaless value of some other wire type
+                        _ => None,
+                    };
+                    Ok(ord.map_or(false, |o| op.test(o)))
+                }),
+        }
+    }
+
+    // Whether eval_at needs the total number of occurrences,
+    // i.e. whether there are any indices counting from the end.
+    fn needs_count(&self) -> bool {
+        match self {
+            &PBFilter::IdxFilter(i) => i < 0,
+            &PBFilter::SliceFilter(start, end) =>
+                start.map_or(false, |i| i < 0) || end.map_or(false, |i| i < 0),
+            &PBFilter::And(ref l, ref r) | &PBFilter::Or(ref l, ref r) =>
+                l.needs_count() || r.needs_count(),
+            &PBFilter::Not(ref f) => f.needs_count(),
+            _ => false,
         }
     }
 }
@@ -60,66 +345,683 @@ pub struct PBExpr {
     pub path: Vec<u32>,
     pub filters: Vec<PBFilter>,
     pub expr_type: Type,
+    // The names and numbers of expr_type's values, if it's an enum.
+    pub enum_values: Option<Vec<(String, i32)>>,
 }
 
-pub struct Subexpr<'a> {
-    path: &'a [u32],
-    filters: &'a [PBFilter],
+// The occurrences of a field in one record: each element if it's a
+// packed repeated scalar, otherwise the record itself.
+pub(crate) enum Occurrences<'a> {
+    Record(Option<PBMessage<'a>>),
+    Packed(PackedIter<'a>),
 }
 
-fn query_helper<'a, 'b, F>(msg: &'a [u8], expr: Subexpr<'b>, callback: &mut F)
-                           -> usize
-    where F : FnMut(PBMessage<'a>) -> bool
-{
-    let targettag = expr.path[0];
-    let ref filter = expr.filters[0];
-    let mut bytes = 0;
-    {
-        // need this block to bound the lifetime of the closure that
-        // mutably borrows bytes
-        let matches = PBIter::new(msg).inspect(|m| bytes += m.bytes.len())
-                                      .filter(|p| p.tag == targettag)
-                                      .filter(|p| filter.eval(p));
-        for m in matches {
-            if expr.path.len() == 1 {
-                if !callback(m) { break; }
-            } else {
-                let subpath = Subexpr { path: &expr.path[1..],
-                                        filters: &expr.filters[1..], };
-                query_helper(m.contents, subpath, callback);
+impl<'a> Iterator for Occurrences<'a> {
+    type Item = DecodeResult<PBMessage<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<PBMessage<'a>>> {
+        match self {
+            &mut Occurrences::Record(ref mut m) => m.take().map(Ok),
+            &mut Occurrences::Packed(ref mut elements) => elements.next(),
+        }
+    }
+}
+
+pub(crate) fn occurrences<'a>(m: PBMessage<'a>, packed: Option<WireType>)
+                   -> Occurrences<'a> {
+    match packed {
+        Some(w) if m.wiretype == WireType::LENGTH_PREFIXED =>
+            Occurrences::Packed(m.unpack(w)),
+        _ => Occurrences::Record(Some(m)),
+    }
+}
+
+impl PBExpr {
+    /// The matches of the expression in msg, in the order they occur.
+    /// Records are decoded only as far as the iterator is advanced. After
+    /// a decoding error, the iterator ends.
+    pub fn matches<'a, 'e>(&'e self, msg: &'a [u8]) -> Matches<'a, 'e> {
+        assert!(self.path.len() > 0);
+        Matches { expr: self, start: Some(msg), levels: vec!(),
+                  scanned: 0, done: false }
+    }
+}
+
+// Where the search is at in one message along the path.
+struct Level<'a> {
+    records: PBIter<'a>,
+    current: Occurrences<'a>,
+    // occurrences of the field seen so far, and in all if the filter
+    // needs to know
+    idx: usize,
+    count: usize,
+}
+
+pub struct Matches<'a, 'e> {
+    expr: &'e PBExpr,
+    start: Option<&'a [u8]>,
+    // one per path element being searched, outermost first
+    levels: Vec<Level<'a>>,
+    scanned: usize,
+    done: bool,
+}
+
+impl<'a, 'e> Matches<'a, 'e> {
+    /// The number of bytes of the outermost message scanned so far.
+    pub fn scanned(&self) -> usize { self.scanned }
+
+    fn push_level(&mut self, msg: &'a [u8]) -> DecodeResult<()> {
+        let depth = self.levels.len();
+        let mut count = 0;
+        if self.expr.filters[depth].needs_count() {
+            let targettag = self.expr.path[depth];
+            let packed = self.packed(depth);
+            for m in PBIter::new(msg) {
+                let m = try!(m);
+                if m.tag != targettag { continue }
+                for e in occurrences(m, packed) {
+                    try!(e);
+                    count += 1;
+                }
+            }
+        }
+        self.levels.push(Level { records: PBIter::new(msg),
+                                 current: Occurrences::Record(None),
+                                 idx: 0, count: count });
+        Ok(())
+    }
+
+    // Only the last field in a path can be a scalar, and so packed.
+    fn packed(&self, depth: usize) -> Option<WireType> {
+        if depth == self.expr.path.len() - 1 {
+            packed_wire_type(self.expr.expr_type)
+        } else {
+            None
+        }
+    }
+
+    fn next_match(&mut self) -> DecodeResult<Option<PBMessage<'a>>> {
+        if let Some(msg) = self.start.take() {
+            try!(self.push_level(msg));
+        }
+        while !self.levels.is_empty() {
+            let depth = self.levels.len() - 1;
+            let packed = self.packed(depth);
+            let (m, idx, count) = {
+                let level = self.levels.last_mut().unwrap();
+                match level.current.next() {
+                    Some(m) => {
+                        level.idx += 1;
+                        (try!(m), level.idx - 1, level.count)
+                    },
+                    None => match level.records.next() {
+                        None => { self.levels.pop(); continue },
+                        Some(m) => {
+                            let m = try!(m);
+                            if depth == 0 { self.scanned += m.bytes.len() }
+                            if m.tag == self.expr.path[depth] {
+                                level.current = occurrences(m, packed);
+                            }
+                            continue
+                        },
+                    },
+                }
+            };
+            if !try!(self.expr.filters[depth].eval_at(&m, idx, count)) {
+                continue
+            }
+            if depth == self.expr.path.len() - 1 {
+                return Ok(Some(m));
+            } else if m.is_nested() {
+                try!(self.push_level(m.contents));
             }
         }
+        Ok(None)
     }
-    bytes
 }
 
-pub fn query<'a, F>(msg: &'a [u8], expr: &PBExpr, callback: &mut F) -> usize
+impl<'a, 'e> Iterator for Matches<'a, 'e> {
+    type Item = DecodeResult<PBMessage<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<PBMessage<'a>>> {
+        if self.done { return None }
+        match self.next_match() {
+            Ok(Some(m)) => Some(Ok(m)),
+            Ok(None) => { self.done = true; None },
+            Err(e) => { self.done = true; Some(Err(e)) },
+        }
+    }
+}
+
+/// A matched field's value, decoded according to its type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PBValue<'a> {
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+    // name is None for a number the enum doesn't define
+    Enum { number: i32, name: Option<&'a str> },
+    Message(&'a [u8]),
+}
+
+/// Decodes a field value of type t. Schemaless values are decoded by
+/// their wire type: varints and fixed-width values as I64, and
+/// length-delimited ones as Bytes. Enum values come without a name.
+pub fn decode_value<'a>(m: &PBMessage<'a>, t: Type)
+                        -> DecodeResult<PBValue<'a>> {
+    let t = value_type(m, t, false);
+    let delimited = m.wiretype == WireType::LENGTH_PREFIXED;
+    if (t.is_stringy() && !delimited) || (t.is_message() && !m.is_nested()) {
+        return Err(DecodeError::WireTypeMismatch(m.wiretype));
+    }
+    Ok(match t {
+        Type::INT32 | Type::SINT32 | Type::SFIXED32 |
+        Type::INT64 | Type::SINT64 | Type::SFIXED64 =>
+            PBValue::I64(try!(m.as_int(t))),
+        Type::UINT32 | Type::FIXED32 =>
+            PBValue::U64(try!(m.as_int(t)) as u64),
+        Type::UINT64 | Type::FIXED64 => PBValue::U64(try!(m.as_uint())),
+        Type::FLOAT => PBValue::F32(try!(m.as_float()) as f32),
+        Type::DOUBLE => PBValue::F64(try!(m.as_float())),
+        Type::BOOL => PBValue::Bool(try!(m.as_uint()) != 0),
+        Type::ENUM =>
+            PBValue::Enum { number: try!(m.as_int(t)) as i32, name: None },
+        Type::STRING => PBValue::Str(try!(m.as_str())),
+        Type::BYTES => PBValue::Bytes(m.contents),
+        Type::MESSAGE => PBValue::Message(m.contents),
+        Type::UNKNOWN => unreachable!(),
+    })
+}
+
+impl PBExpr {
+    /// Decodes a match of this expression, as decode_value does, naming
+    /// enum values.
+    pub fn value<'a>(&'a self, m: &PBMessage<'a>) -> DecodeResult<PBValue<'a>> {
+        match try!(decode_value(m, self.expr_type)) {
+            PBValue::Enum { number, .. } => {
+                let name = self.enum_values.as_ref().and_then(
+                    |values| values.iter().find(|v| v.1 == number))
+                    .map(|v| &v.0[..]);
+                Ok(PBValue::Enum { number: number, name: name })
+            },
+            v => Ok(v),
+        }
+    }
+
+    /// The decoded values of the matches in msg, as with matches.
+    pub fn values<'a>(&'a self, msg: &'a [u8]) -> Values<'a> {
+        Values { expr: self, matches: self.matches(msg) }
+    }
+}
+
+pub struct Values<'a> {
+    expr: &'a PBExpr,
+    matches: Matches<'a, 'a>,
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = DecodeResult<PBValue<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<PBValue<'a>>> {
+        let expr = self.expr;
+        self.matches.next().map(|m| m.and_then(|m| expr.value(&m)))
+    }
+}
+
+/// Calls callback with each match of expr in msg, until it returns false.
+/// Returns the number of bytes of msg scanned.
+pub fn query<'a, F>(msg: &'a [u8], expr: &PBExpr, callback: &mut F)
+                    -> DecodeResult<usize>
     where F : FnMut(PBMessage<'a>) -> bool
 {
-    assert!(expr.path.len() > 0);
-    let subpath = Subexpr { path: &expr.path[..],
-                            filters: &expr.filters[..] };
-    query_helper(msg, subpath, callback)
+    let mut matches = expr.matches(msg);
+    while let Some(m) = matches.next() {
+        if !callback(try!(m)) { break }
+    }
+    Ok(matches.scanned())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::bytes::Regex;
+    use ::compile;
+    use ::descriptors::native::tests::{transit_pool, field_bytes, field_varint,
+                                       stop, feed_with};
 
-use std::io::BufRead;
-use std::io::Read;
+    fn run(msg: &[u8], expr: &PBExpr) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        query(msg, expr, &mut |m| { out.push(m.contents.to_vec()); true })
+            .unwrap();
+        out
+    }
 
-pub fn query_stream<F>(stream: &mut BufRead,
-                    expr: &PBExpr,
-                    mut callback: F) -> ()
-    where F : FnMut(PBMessage) -> bool,
-{
-    loop {
-        let l = {
-            let buf = stream.fill_buf().unwrap();
-            println!("buflen: {}", buf.len());
-            if buf.len() == 0 { return; }            
-            query(buf, expr, &mut callback)
+    // two occurrences of field 1: "Main St" and "Elm St"
+    const STOPS: &'static [u8] = b"\x0a\x07Main St\x0a\x06Elm St";
+
+    #[test]
+    fn test_rx_filter() {
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::RxFilter {
+                rx: Regex::new("^Main").unwrap(),
+                path: PBPath::At, invert: false }],
+            expr_type: Type::STRING,
+            enum_values: None,
         };
-        if l == 0 { return; }
-        println!("consuming {}", l);
-        stream.consume(l);
+        assert_eq!(run(STOPS, &expr), vec![b"Main St".to_vec()]);
+
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::RxFilter {
+                rx: Regex::new("^Main").unwrap(),
+                path: PBPath::At, invert: true }],
+            expr_type: Type::STRING,
+            enum_values: None,
+        };
+        assert_eq!(run(STOPS, &expr), vec![b"Elm St".to_vec()]);
+    }
+
+    // three occurrences of field 1, each with repeated int field 2
+    // and string field 3: {2: [3, 7], 3: "A"}, {2: [5], 3: "B"}, {3: "C"}
+    const ENTITIES: &'static [u8] =
+        b"\x0a\x07\x10\x03\x10\x07\x1a\x01A\x0a\x05\x10\x05\x1a\x01B\x0a\x03\x1a\x01C";
+
+    fn subpath(tag: u32, t: Type) -> PBPath {
+        PBPath::Path(PBExpr { path: vec![tag],
+                              filters: vec![PBFilter::TrueFilter],
+                              expr_type: t,
+                              enum_values: None })
+    }
+
+    #[test]
+    fn test_in_filter() {
+        let strs = ["A", "C"].iter().map(|s| s.to_string()).collect();
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InStrFilter(subpath(3, Type::STRING), strs)],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(ENTITIES, &expr).len(), 2);
+
+        let strs = ["D"].iter().map(|s| s.to_string()).collect();
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InStrFilter(subpath(3, Type::STRING), strs)],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(ENTITIES, &expr).len(), 0);
+
+        // matches the first entity on its second element only
+        let ints = [7, 5].iter().cloned().collect();
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InIntFilter(subpath(2, Type::INT32),
+                                                Type::INT32, ints)],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(ENTITIES, &expr).len(), 2);
+
+        let ints = [1, 2].iter().cloned().collect();
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InIntFilter(subpath(2, Type::INT32),
+                                                Type::INT32, ints)],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(ENTITIES, &expr).len(), 0);
+    }
+
+    fn index_expr(filter: PBFilter) -> PBExpr {
+        PBExpr { path: vec![1, 3],
+                 filters: vec![filter, PBFilter::TrueFilter],
+                 expr_type: Type::STRING,
+                 enum_values: None }
+    }
+
+    fn cmp_expr(atom: PBItem, pathtype: Type, op: CmpOp) -> PBExpr {
+        PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::CmpFilter { atom: atom,
+                                                path: subpath(2, pathtype),
+                                                pathtype: pathtype,
+                                                op: op }],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        }
+    }
+
+    #[test]
+    fn test_repeated_eq() {
+        // = and ~ match if any occurrence does, like in
+        let filter = |f| PBExpr { path: vec![1], filters: vec![f],
+                                  expr_type: Type::MESSAGE, enum_values: None };
+        let eq = |i| filter(PBFilter::EqFilter { atom: PBItem::Int(i),
+                                                 path: subpath(2, Type::INT32),
+                                                 pathtype: Type::INT32,
+                                                 invert: false });
+        let within = |i| filter(PBFilter::InIntFilter(
+            subpath(2, Type::INT32), Type::INT32,
+            [i as i128].iter().cloned().collect()));
+        for &i in &[3, 7, 5, 4] {
+            assert_eq!(run(ENTITIES, &eq(i)), run(ENTITIES, &within(i)));
+        }
+        assert_eq!(run(ENTITIES, &eq(7)).len(), 1);
+
+        // {2: [3, 7] packed}
+        let packed = b"\x0a\x04\x12\x02\x03\x07";
+        assert_eq!(run(packed, &eq(7)).len(), 1);
+
+        // {3: "A", 3: "B"}
+        let names = b"\x0a\x06\x1a\x01A\x1a\x01B";
+        let rx = filter(PBFilter::RxFilter { rx: Regex::new("B").unwrap(),
+                                             path: subpath(3, Type::STRING),
+                                             invert: false });
+        assert_eq!(run(names, &rx).len(), 1);
+    }
+
+    #[test]
+    fn test_cmp_filter() {
+        let count = |a, op| run(ENTITIES, &cmp_expr(a, Type::INT32, op)).len();
+        assert_eq!(count(PBItem::Int(5), CmpOp::Gt), 1);
+        assert_eq!(count(PBItem::Int(5), CmpOp::Ge), 2);
+        assert_eq!(count(PBItem::Int(3), CmpOp::Lt), 0);
+        assert_eq!(count(PBItem::Int(3), CmpOp::Le), 1);
+        assert_eq!(count(PBItem::Float(4.5), CmpOp::Lt), 1);
+        assert_eq!(count(PBItem::Float(7.5), CmpOp::Lt), 2);
+
+        // {2: 2.5f} and {2: 0.5f}
+        let speeds = b"\x0a\x05\x15\x00\x00\x20\x40\x0a\x05\x15\x00\x00\x00\x3f";
+        let count = |a, op| run(speeds, &cmp_expr(a, Type::FLOAT, op)).len();
+        assert_eq!(count(PBItem::Float(2.5), CmpOp::Le), 2);
+        assert_eq!(count(PBItem::Float(2.5), CmpOp::Lt), 1);
+        assert_eq!(count(PBItem::Float(0.5), CmpOp::Gt), 1);
+
+        // {2: u64::MAX}, {2: -2 (zigzag)}
+        let ids = b"\x0a\x0b\x10\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01\x0a\x02\x10\x03";
+        let count = |a, t, op| run(ids, &cmp_expr(a, t, op)).len();
+        assert_eq!(count(PBItem::Int(i64::max_value()), Type::UINT64, CmpOp::Gt), 1);
+        assert_eq!(count(PBItem::Int(0), Type::UINT64, CmpOp::Gt), 2);
+        assert_eq!(count(PBItem::Int(0), Type::INT64, CmpOp::Lt), 1);
+        assert_eq!(count(PBItem::Int(-2), Type::SINT64, CmpOp::Le), 2);
+    }
+
+    #[test]
+    fn test_bool_filter() {
+        let has_a = || PBFilter::InStrFilter(
+            subpath(3, Type::STRING), ["A"].iter().map(|s| s.to_string()).collect());
+        let big = || PBFilter::CmpFilter { atom: PBItem::Int(4),
+                                           path: subpath(2, Type::INT32),
+                                           pathtype: Type::INT32,
+                                           op: CmpOp::Gt };
+        let count = |f| run(ENTITIES, &PBExpr { path: vec![1],
+                                                filters: vec![f],
+                                                expr_type: Type::MESSAGE,
+                                                enum_values: None })
+                        .len();
+        assert_eq!(count(PBFilter::And(Box::new(has_a()), Box::new(big()))), 1);
+        assert_eq!(count(PBFilter::Or(Box::new(has_a()), Box::new(big()))), 2);
+        assert_eq!(count(PBFilter::Not(Box::new(big()))), 1);
+        assert_eq!(count(PBFilter::And(Box::new(PBFilter::Not(Box::new(has_a()))),
+                                       Box::new(big()))), 1);
+        assert_eq!(count(PBFilter::FalseFilter), 0);
+    }
+
+    #[test]
+    fn test_path_cmp_filter() {
+        // {2: 3, 5: 3}, {2: 4, 5: 3}, {2: [1, 9], 5: 5}, {2: 1}
+        let msgs = b"\x0a\x04\x10\x03\x28\x03\x0a\x04\x10\x04\x28\x03\
+                     \x0a\x06\x10\x01\x10\x09\x28\x05\x0a\x02\x10\x01";
+        let count = |op, rhstype| run(msgs, &PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::PathCmpFilter {
+                lhs: subpath(2, Type::INT32), lhstype: Type::INT32,
+                rhs: subpath(5, rhstype), rhstype: rhstype, op: op }],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        }).len();
+        assert_eq!(count(CmpOp::Eq, Type::INT32), 1);
+        assert_eq!(count(CmpOp::Ne, Type::INT32), 2);
+        assert_eq!(count(CmpOp::Gt, Type::INT32), 2);
+        assert_eq!(count(CmpOp::Lt, Type::INT32), 1);
+        assert_eq!(count(CmpOp::Le, Type::INT64), 2);
+
+        // {3: "A", 6: "A"}, {3: "B", 6: "A"}
+        let msgs = b"\x0a\x06\x1a\x01A\x32\x01A\x0a\x06\x1a\x01B\x32\x01A";
+        let count = |op| run(msgs, &PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::PathCmpFilter {
+                lhs: subpath(3, Type::STRING), lhstype: Type::STRING,
+                rhs: subpath(6, Type::BYTES), rhstype: Type::BYTES, op: op }],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        }).len();
+        assert_eq!(count(CmpOp::Eq), 1);
+        assert_eq!(count(CmpOp::Ne), 1);
+    }
+
+    #[test]
+    fn test_has_filter() {
+        let has = |tag, t, zero, inv| PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::HasFilter { path: subpath(tag, t),
+                                                pathtype: t,
+                                                zero_is_present: zero,
+                                                invert: inv }],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(ENTITIES, &has(2, Type::INT32, true, false)).len(), 2);
+        assert_eq!(run(ENTITIES, &has(2, Type::INT32, true, true)).len(), 1);
+        assert_eq!(run(ENTITIES, &has(3, Type::STRING, true, false)).len(), 3);
+        assert_eq!(run(ENTITIES, &has(4, Type::STRING, true, false)).len(), 0);
+
+        // {2: 0, 3: ""}, {2: 1, 4: {}}
+        let zeros = b"\x0a\x04\x10\x00\x1a\x00\x0a\x04\x10\x01\x22\x00";
+        assert_eq!(run(zeros, &has(2, Type::INT32, true, false)).len(), 2);
+        assert_eq!(run(zeros, &has(2, Type::INT32, false, false)).len(), 1);
+        assert_eq!(run(zeros, &has(3, Type::STRING, true, false)).len(), 1);
+        assert_eq!(run(zeros, &has(3, Type::STRING, false, false)).len(), 0);
+        assert_eq!(run(zeros, &has(3, Type::STRING, false, true)).len(), 2);
+        assert_eq!(run(zeros, &has(4, Type::MESSAGE, false, false)).len(), 1);
+
+        // {3: "\0"}
+        let nul = b"\x0a\x03\x1a\x01\x00";
+        assert_eq!(run(nul, &has(3, Type::BYTES, false, false)).len(), 1);
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let expr = index_expr(PBFilter::TrueFilter);
+        let mut out = 0;
+        // the second entity's length runs past the end of the buffer
+        let r = query(b"\x0a\x03\x1a\x01A\x0a\x09\x1a\x01B", &expr,
+                      &mut |_| { out += 1; true });
+        assert_eq!(r, Err(DecodeError::LengthOverflow));
+        assert_eq!(out, 1);
+
+        // errors inside a filter's sub-path are reported too
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InStrFilter(
+                subpath(3, Type::STRING),
+                ["B"].iter().map(|s| s.to_string()).collect())],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        let r = query(b"\x0a\x03\x1a\x05B", &expr, &mut |_| true);
+        assert_eq!(r, Err(DecodeError::LengthOverflow));
+
+    }
+
+    #[test]
+    fn test_matches() {
+        let expr = index_expr(PBFilter::TrueFilter);
+        // two entities, the second with two names, then one whose length
+        // runs past the end of the buffer
+        let msgs = b"\x0a\x03\x1a\x01A\x0a\x06\x1a\x01B\x1a\x01C\x0a\x09";
+        let names: Vec<_> = expr.matches(msgs).take(3)
+            .map(|m| m.unwrap().contents).collect();
+        assert_eq!(names, vec![b"A", b"B", b"C"]);
+
+        let mut matches = expr.matches(msgs);
+        assert_eq!(matches.next().map(|m| m.unwrap().contents), Some(&b"A"[..]));
+        assert_eq!(matches.scanned(), 5);
+
+        let r: DecodeResult<Vec<_>> = expr.matches(msgs).collect();
+        assert_eq!(r, Err(DecodeError::LengthOverflow));
+        let mut matches = expr.matches(msgs).skip(3);
+        assert_eq!(matches.next(), Some(Err(DecodeError::LengthOverflow)));
+        assert_eq!(matches.next(), None);
+
+        assert_eq!(expr.matches(msgs).filter_map(Result::ok)
+                       .filter(|m| m.contents != b"B").count(), 2);
+        assert_eq!(expr.matches(b"").next(), None);
+    }
+
+    #[test]
+    fn test_nested_any_path() {
+        // {4: {3: "A"}, 4: {3: "B"}}: a match in the first occurrence of
+        // a multi-part path must not be forgotten on the second.
+        let msgs = b"\x0a\x0a\x22\x03\x1a\x01A\x22\x03\x1a\x01B";
+        let nested = PBPath::Path(PBExpr {
+            path: vec![4, 3],
+            filters: vec![PBFilter::TrueFilter, PBFilter::TrueFilter],
+            expr_type: Type::STRING,
+            enum_values: None });
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InStrFilter(
+                nested, ["A"].iter().map(|s| s.to_string()).collect())],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(msgs, &expr).len(), 1);
+    }
+
+    #[test]
+    fn test_oneof_which() {
+        let platform = Oneof { names: vec!["track".to_string(),
+                                           "bay".to_string()],
+                               cases: vec![6, 7] };
+        assert_eq!(platform.which(b""), Ok(None));
+        assert_eq!(platform.which(&field_bytes(1, b"a")), Ok(None));
+        let mut both = field_bytes(6, b"1");
+        both.extend(field_bytes(7, b"2"));
+        assert_eq!(platform.which(&both), Ok(Some("bay")));
+        // the last member on the wire wins, even if it was set before
+        both.extend(field_bytes(6, b"3"));
+        assert_eq!(platform.which(&both), Ok(Some("track")));
+        assert_eq!(platform.which(b"\x32\x05"),
+                   Err(DecodeError::LengthOverflow));
+    }
+
+    #[test]
+    fn test_idx_filter() {
+        let names = |f| run(ENTITIES, &index_expr(f)).concat();
+        assert_eq!(names(PBFilter::IdxFilter(0)), b"A");
+        assert_eq!(names(PBFilter::IdxFilter(2)), b"C");
+        assert_eq!(names(PBFilter::IdxFilter(3)), b"");
+        assert_eq!(names(PBFilter::IdxFilter(-1)), b"C");
+        assert_eq!(names(PBFilter::IdxFilter(-3)), b"A");
+        assert_eq!(names(PBFilter::IdxFilter(-4)), b"");
+        assert_eq!(names(PBFilter::SliceFilter(Some(1), None)), b"BC");
+        assert_eq!(names(PBFilter::SliceFilter(Some(0), Some(2))), b"AB");
+        assert_eq!(names(PBFilter::SliceFilter(None, Some(-1))), b"AB");
+        assert_eq!(names(PBFilter::SliceFilter(Some(-2), None)), b"BC");
+        assert_eq!(names(PBFilter::SliceFilter(Some(-5), Some(5))), b"ABC");
+        assert_eq!(names(PBFilter::SliceFilter(Some(2), Some(1))), b"");
+        assert_eq!(names(PBFilter::Not(Box::new(PBFilter::IdxFilter(-1)))),
+                   b"AB");
+    }
+
+    // repeated int32 field 1: [3, 12] packed, 20 unpacked, then [15] packed
+    const SAMPLES: &'static [u8] = b"\x0a\x02\x03\x0c\x08\x14\x0a\x01\x0f";
+
+    fn samples(filter: PBFilter) -> Vec<i64> {
+        let expr = PBExpr { path: vec![1], filters: vec![filter],
+                            expr_type: Type::INT32,
+                            enum_values: None };
+        let mut out = Vec::new();
+        query(SAMPLES, &expr, &mut |m| {
+            out.push(m.as_int(Type::INT32).unwrap());
+            true
+        }).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_packed() {
+        assert_eq!(samples(PBFilter::TrueFilter), vec![3, 12, 20, 15]);
+        assert_eq!(samples(PBFilter::CmpFilter { atom: PBItem::Int(10),
+                                                 path: PBPath::At,
+                                                 pathtype: Type::INT32,
+                                                 op: CmpOp::Gt }),
+                   vec![12, 20, 15]);
+        assert_eq!(samples(PBFilter::IdxFilter(1)), vec![12]);
+        assert_eq!(samples(PBFilter::IdxFilter(-1)), vec![15]);
+        assert_eq!(samples(PBFilter::SliceFilter(Some(1), Some(-1))),
+                   vec![12, 20]);
+        let ints = [3, 15].iter().cloned().collect();
+        assert_eq!(samples(PBFilter::InIntFilter(PBPath::At, Type::INT32, ints)),
+                   vec![3, 15]);
+
+        // {2: [3, 7] packed}, {2: 5}: filters on a parent see each element
+        let msgs = b"\x0a\x04\x12\x02\x03\x07\x0a\x02\x10\x05";
+        let ints = [7].iter().cloned().collect();
+        let expr = PBExpr {
+            path: vec![1],
+            filters: vec![PBFilter::InIntFilter(subpath(2, Type::INT32),
+                                                Type::INT32, ints)],
+            expr_type: Type::MESSAGE,
+            enum_values: None,
+        };
+        assert_eq!(run(msgs, &expr).len(), 1);
+
+        // a packed string would just be a string
+        let expr = PBExpr { path: vec![1], filters: vec![PBFilter::TrueFilter],
+                            expr_type: Type::STRING,
+                            enum_values: None };
+        assert_eq!(run(STOPS, &expr).len(), 2);
+    }
+
+    #[test]
+    fn test_values() {
+        let pool = transit_pool();
+        let feed = pool.message("transit.Feed").unwrap();
+        let tram = stop("a", &[(4, 1), (5, 1)]);
+        let mut buf = feed_with(&[tram.clone(), field_varint(4, 7)]);
+        buf.extend(field_varint(2, u64::max_value()));
+        buf.extend(field_varint(3, 9));
+
+        let expr = compile("stops.kind", feed).unwrap();
+        let kinds: Vec<_> = expr.values(&buf).map(Result::unwrap).collect();
+        assert_eq!(kinds, vec!(PBValue::Enum { number: 1, name: Some("TRAM") },
+                               PBValue::Enum { number: 7, name: None }));
+
+        let value = |q| {
+            let expr = compile(q, feed).unwrap();
+            let v = expr.values(&buf).next().unwrap().unwrap();
+            format!("{:?}", v)
+        };
+        assert_eq!(value("stops.name"), "Str(\"a\")");
+        assert_eq!(value("stops.accessible"), "Bool(true)");
+        assert_eq!(value("timestamp"), "U64(18446744073709551615)");
+        assert_eq!(value("stops[0]"), format!("Message({:?})", tram));
+        // field 3 isn't in the schema
+        assert_eq!(value("#3"), "I64(9)");
+
+        // a string field holding a varint
+        let expr = compile("stops.name", feed).unwrap();
+        assert!(expr.values(&field_bytes(1, &field_varint(1, 3))).next()
+                .unwrap().is_err());
     }
 }
@@ -0,0 +1,433 @@
+// Querying streams of messages. How the messages are framed in the
+// stream is up to the RecordReader passed in; the common container formats
+// have readers here.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, BufRead};
+use base64;
+use pbiter::{PBMessage, DecodeError, MAX_VARINT_LEN};
+use query::{query, PBExpr};
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    // A record that ended early or couldn't be decoded, with the byte
+    // offset in the stream where its framing starts.
+    Decode { offset: u64, error: DecodeError },
+    // A record whose framing is damaged: a bad checksum, invalid base64.
+    Corrupt { offset: u64, message: &'static str },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StreamError::Io(ref e) => write!(f, "{}", e),
+            &StreamError::Decode { offset, error } =>
+                write!(f, "record at byte {}: {}", offset, error),
+            &StreamError::Corrupt { offset, message } =>
+                write!(f, "record at byte {}: {}", offset, message),
+        }
+    }
+}
+
+impl Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> StreamError { StreamError::Io(e) }
+}
+
+pub type StreamResult<T> = Result<T, StreamError>;
+
+/// A source of records, one serialized message each.
+pub trait RecordReader {
+    /// Reads the next record into buf, returning the offset in the stream
+    /// where its framing starts, or None at the end of the stream.
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>>;
+}
+
+impl<'a, T: RecordReader + ?Sized> RecordReader for &'a mut T {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        (**self).next_record(buf)
+    }
+}
+
+impl<T: RecordReader + ?Sized> RecordReader for Box<T> {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        (**self).next_record(buf)
+    }
+}
+
+// Fills buf from stream as far as it'll go, returning how much was read.
+// Short only at the end of the stream.
+fn read_full<R: Read>(stream: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match stream.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+// Replaces buf with the next len bytes of stream, returning whether there
+// were that many.
+fn read_body<R: Read>(stream: &mut R, len: u64, buf: &mut Vec<u8>)
+                      -> io::Result<bool> {
+    buf.clear();
+    let read = try!(stream.take(len).read_to_end(buf));
+    Ok(read as u64 == len)
+}
+
+fn truncated(offset: u64) -> StreamError {
+    StreamError::Decode { offset: offset, error: DecodeError::Truncated }
+}
+
+/// Reads records framed the way protobuf's writeDelimitedTo writes them: a
+/// varint length, then that many bytes of message. Records are read
+/// whole, however much bigger than the stream's buffer they are.
+pub struct DelimitedReader<R> {
+    stream: R,
+    offset: u64,
+}
+
+impl<R: Read> DelimitedReader<R> {
+    pub fn new(stream: R) -> DelimitedReader<R> {
+        DelimitedReader { stream: stream, offset: 0 }
+    }
+}
+
+impl<R: Read> RecordReader for DelimitedReader<R> {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        let start = self.offset;
+        let mut len = 0u64;
+        for i in 0..MAX_VARINT_LEN + 1 {
+            if i == MAX_VARINT_LEN {
+                return Err(StreamError::Decode {
+                    offset: start, error: DecodeError::OverlongVarint });
+            }
+            let mut b = [0];
+            if try!(read_full(&mut self.stream, &mut b)) == 0 {
+                return if i == 0 { Ok(None) } else { Err(truncated(start)) };
+            }
+            self.offset += 1;
+            len |= ((b[0] & 0x7f) as u64).wrapping_shl(i as u32 * 7);
+            if b[0] & 0x80 == 0 { break }
+        }
+
+        let complete = try!(read_body(&mut self.stream, len, buf));
+        self.offset += buf.len() as u64;
+        if !complete { return Err(truncated(start)) }
+        Ok(Some(start))
+    }
+}
+
+/// Reads records with a 4-byte big-endian length before each.
+pub struct BigEndianReader<R> {
+    stream: R,
+    offset: u64,
+}
+
+impl<R: Read> BigEndianReader<R> {
+    pub fn new(stream: R) -> BigEndianReader<R> {
+        BigEndianReader { stream: stream, offset: 0 }
+    }
+}
+
+impl<R: Read> RecordReader for BigEndianReader<R> {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        let start = self.offset;
+        let mut header = [0; 4];
+        let read = try!(read_full(&mut self.stream, &mut header));
+        self.offset += read as u64;
+        if read == 0 { return Ok(None) }
+        if read < header.len() { return Err(truncated(start)) }
+
+        let len = header.iter().fold(0u64, |n, &b| n << 8 | b as u64);
+        let complete = try!(read_body(&mut self.stream, len, buf));
+        self.offset += buf.len() as u64;
+        if !complete { return Err(truncated(start)) }
+        Ok(Some(start))
+    }
+}
+
+// CRC-32C (Castagnoli), reflected, as TFRecord uses.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0x82f63b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+// TFRecord stores its checksums rotated and offset, so that a checksum of
+// data that itself holds checksums doesn't come out degenerate.
+fn masked_crc32c(data: &[u8]) -> u32 {
+    crc32c(data).rotate_right(15).wrapping_add(0xa282ead8)
+}
+
+fn le_u32(b: &[u8]) -> u32 {
+    b.iter().rev().fold(0u32, |n, &b| n << 8 | b as u32)
+}
+
+/// Reads TensorFlow TFRecord files: each record is a little-endian u64
+/// length, the masked CRC-32C of those 8 bytes, the data, then the masked
+/// CRC-32C of the data. Both checksums are verified.
+pub struct TFRecordReader<R> {
+    stream: R,
+    offset: u64,
+}
+
+impl<R: Read> TFRecordReader<R> {
+    pub fn new(stream: R) -> TFRecordReader<R> {
+        TFRecordReader { stream: stream, offset: 0 }
+    }
+}
+
+impl<R: Read> RecordReader for TFRecordReader<R> {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        let start = self.offset;
+        let corrupt = |message| StreamError::Corrupt {
+            offset: start, message: message };
+        let mut header = [0; 12];
+        let read = try!(read_full(&mut self.stream, &mut header));
+        self.offset += read as u64;
+        if read == 0 { return Ok(None) }
+        if read < header.len() { return Err(truncated(start)) }
+        if masked_crc32c(&header[..8]) != le_u32(&header[8..]) {
+            return Err(corrupt("length checksum mismatch"));
+        }
+
+        let len = header[..8].iter().rev().fold(0u64, |n, &b| n << 8 | b as u64);
+        let complete = try!(read_body(&mut self.stream, len, buf));
+        self.offset += buf.len() as u64;
+        if !complete { return Err(truncated(start)) }
+
+        let mut footer = [0; 4];
+        let read = try!(read_full(&mut self.stream, &mut footer));
+        self.offset += read as u64;
+        if read < footer.len() { return Err(truncated(start)) }
+        if masked_crc32c(buf) != le_u32(&footer) {
+            return Err(corrupt("data checksum mismatch"));
+        }
+        Ok(Some(start))
+    }
+}
+
+/// Reads one base64-encoded record per line. Line endings may be \n or
+/// \r\n; an empty line is an empty record.
+pub struct Base64LineReader<R> {
+    stream: R,
+    offset: u64,
+    line: Vec<u8>,
+}
+
+impl<R: BufRead> Base64LineReader<R> {
+    pub fn new(stream: R) -> Base64LineReader<R> {
+        Base64LineReader { stream: stream, offset: 0, line: vec!() }
+    }
+}
+
+impl<R: BufRead> RecordReader for Base64LineReader<R> {
+    fn next_record(&mut self, buf: &mut Vec<u8>) -> StreamResult<Option<u64>> {
+        let start = self.offset;
+        self.line.clear();
+        let read = try!(self.stream.read_until(b'\n', &mut self.line));
+        self.offset += read as u64;
+        if read == 0 { return Ok(None) }
+
+        let mut line = &self.line[..];
+        if line.ends_with(b"\n") { line = &line[..line.len() - 1] }
+        if line.ends_with(b"\r") { line = &line[..line.len() - 1] }
+        buf.clear();
+        try!(base64::decode(line, buf).map_err(
+            |message| StreamError::Corrupt { offset: start, message: message }));
+        Ok(Some(start))
+    }
+}
+
+/// Runs expr over each record from records. The callback gets each match
+/// along with the offset of the record it's in, and can return false to
+/// stop.
+pub fn query_stream<R, F>(mut records: R, expr: &PBExpr, mut callback: F)
+                          -> StreamResult<()>
+    where R: RecordReader, F: FnMut(u64, PBMessage) -> bool
+{
+    let mut buf = vec!();
+    while let Some(offset) = try!(records.next_record(&mut buf)) {
+        let mut more = true;
+        try!(query(&buf, expr, &mut |m| { more = callback(offset, m); more })
+             .map_err(|e| StreamError::Decode { offset: offset, error: e }));
+        if !more { break }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+    use query::PBFilter;
+    use descriptors::Type;
+
+    fn delimited(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec!();
+        for r in records {
+            let mut len = r.len();
+            while len >= 0x80 {
+                out.push((len as u8 & 0x7f) | 0x80);
+                len >>= 7;
+            }
+            out.push(len as u8);
+            out.extend(r);
+        }
+        out
+    }
+
+    fn names() -> PBExpr {
+        PBExpr { path: vec![1], filters: vec![PBFilter::TrueFilter],
+                 expr_type: Type::STRING,
+                 enum_values: None }
+    }
+
+    #[test]
+    fn test_query_stream() {
+        // the middle record is much bigger than the reader's buffer
+        let big = vec![b'x'; 300];
+        let mut second = vec![0x0a, 0xac, 0x02];
+        second.extend(&big);
+        let stream = delimited(&[b"\x0a\x01a\x0a\x01b".to_vec(), second,
+                                 vec![], b"\x0a\x01c".to_vec()]);
+
+        let mut found = vec!();
+        let reader = BufReader::with_capacity(16, Cursor::new(&stream));
+        query_stream(DelimitedReader::new(reader), &names(), |offset, m| {
+            found.push((offset, m.contents.len()));
+            true
+        }).unwrap();
+        assert_eq!(found, vec![(0, 1), (0, 1), (7, 300), (313, 1)]);
+
+        let mut count = 0;
+        query_stream(DelimitedReader::new(Cursor::new(&stream)), &names(),
+                     |_, _| { count += 1; count < 2 }).unwrap();
+        assert_eq!(count, 2);
+
+        query_stream(DelimitedReader::new(Cursor::new(&[])), &names(),
+                     |_, _| panic!()).unwrap();
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let run = |stream: &[u8]| {
+            query_stream(DelimitedReader::new(Cursor::new(stream)), &names(),
+                         |_, _| true)
+        };
+        match run(b"\x03\x0a\x01a\x05\x0a\x01") {
+            Err(StreamError::Decode { offset: 4,
+                                      error: DecodeError::Truncated }) => (),
+            r => panic!("{:?}", r),
+        }
+        // a complete record that doesn't decode
+        match run(b"\x03\x0a\x01a\x02\x0a\x05") {
+            Err(StreamError::Decode { offset: 4,
+                                      error: DecodeError::LengthOverflow }) => (),
+            r => panic!("{:?}", r),
+        }
+        match run(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01") {
+            Err(StreamError::Decode { offset: 0,
+                                      error: DecodeError::OverlongVarint }) => (),
+            r => panic!("{:?}", r),
+        }
+        match run(b"\x80") {
+            Err(StreamError::Decode { error: DecodeError::Truncated, .. }) => (),
+            r => panic!("{:?}", r),
+        }
+        let e = run(b"\x01").unwrap_err();
+        assert_eq!(e.to_string(), "record at byte 0: truncated message");
+    }
+
+    fn tfrecords(records: &[&[u8]]) -> Vec<u8> {
+        let mut out = vec!();
+        for r in records {
+            let len: Vec<u8> = (0..8).map(|i| (r.len() >> i * 8) as u8).collect();
+            let len_crc = masked_crc32c(&len);
+            out.extend(&len);
+            out.extend((0..4).map(|i| (len_crc >> i * 8) as u8));
+            out.extend(*r);
+            let crc = masked_crc32c(r);
+            out.extend((0..4).map(|i| (crc >> i * 8) as u8));
+        }
+        out
+    }
+
+    fn collect<R: RecordReader>(records: R) -> StreamResult<Vec<(u64, String)>> {
+        let mut found = vec!();
+        try!(query_stream(records, &names(), |offset, m| {
+            found.push((offset, m.as_str().unwrap().to_string()));
+            true
+        }));
+        Ok(found)
+    }
+
+    #[test]
+    fn test_framings() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        let expected = |last| vec![(0, "a".to_string()), (0, "b".to_string()),
+                                   (last, "c".to_string())];
+
+        let stream = b"\x00\x00\x00\x06\x0a\x01a\x0a\x01b\x00\x00\x00\x00\
+                       \x00\x00\x00\x03\x0a\x01c";
+        assert_eq!(collect(BigEndianReader::new(&stream[..])).unwrap(),
+                   expected(14));
+        match collect(BigEndianReader::new(&stream[..16])) {
+            Err(StreamError::Decode { offset: 14,
+                                      error: DecodeError::Truncated }) => (),
+            r => panic!("{:?}", r),
+        }
+
+        let stream = tfrecords(&[b"\x0a\x01a\x0a\x01b", b"", b"\x0a\x01c"]);
+        assert_eq!(collect(TFRecordReader::new(&stream[..])).unwrap(),
+                   expected(38));
+        let mut damaged = stream.clone();
+        damaged[30] ^= 1;
+        match collect(TFRecordReader::new(&damaged[..])) {
+            Err(e) => assert_eq!(e.to_string(),
+                                 "record at byte 22: length checksum mismatch"),
+            r => panic!("{:?}", r),
+        }
+        damaged = stream.clone();
+        damaged[51] ^= 1;
+        match collect(TFRecordReader::new(&damaged[..])) {
+            Err(e) => assert_eq!(e.to_string(),
+                                 "record at byte 38: data checksum mismatch"),
+            r => panic!("{:?}", r),
+        }
+
+        let stream = b"CgFhCgFi\r\n\nCgFj";
+        assert_eq!(collect(Base64LineReader::new(&stream[..])).unwrap(),
+                   expected(11));
+        match collect(Base64LineReader::new(&b"CgFh\nCg!h\n"[..])) {
+            Err(StreamError::Corrupt { offset: 5, .. }) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}